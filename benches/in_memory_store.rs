@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serdo::cmd::Cmd;
+use serdo::undo_store::{InMemoryUndoStore, UndoStore};
+
+const HISTORY_LEN: usize = 10_000;
+
+struct AddCmd(i64);
+
+impl Cmd for AddCmd {
+    type Model = Sum;
+
+    fn redo(&self, model: &mut Self::Model) {
+        model.0 += self.0;
+    }
+
+    fn undo(&self, model: &mut Self::Model) {
+        model.0 -= self.0;
+    }
+}
+
+#[derive(Default)]
+struct Sum(i64);
+
+fn full_store() -> InMemoryUndoStore<AddCmd, Sum, ()> {
+    let mut store = InMemoryUndoStore::new(HISTORY_LEN);
+    for i in 0..HISTORY_LEN as i64 {
+        store.add_cmd(AddCmd(i));
+    }
+    store
+}
+
+// Each command added to the full history evicts the oldest one.
+fn add_at_capacity(c: &mut Criterion) {
+    c.bench_function("add_cmd at capacity (10k)", |b| {
+        b.iter_batched_ref(full_store, |store| {
+            for i in 0..1000 {
+                store.add_cmd(AddCmd(i));
+            }
+        }, BatchSize::LargeInput)
+    });
+
+    // The previous eviction that shifted a Vec on every command.
+    c.bench_function("Vec::remove(0) eviction (10k)", |b| {
+        b.iter_batched_ref(|| (0..HISTORY_LEN as i64).map(AddCmd).collect::<Vec<_>>(), |store| {
+            for i in 0..1000 {
+                store.remove(0);
+                store.push(AddCmd(i));
+            }
+        }, BatchSize::LargeInput)
+    });
+}
+
+fn undo_redo_all(c: &mut Criterion) {
+    c.bench_function("undo/redo all (10k)", |b| {
+        b.iter_batched_ref(full_store, |store| {
+            store.go_to(0);
+            store.go_to(HISTORY_LEN as i64);
+        }, BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, add_at_capacity, undo_redo_all);
+criterion_main!(benches);
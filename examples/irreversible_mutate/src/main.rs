@@ -1,5 +1,5 @@
 use std::{io, borrow::Cow};
-use serdo::{cmd::Cmd, undo_store::{UndoStore, InMemoryUndoStore}};
+use serdo::{cmd::Cmd, undo_store::{UndoStore, InMemoryUndoStore, InMemoryStoreErr}};
 
 enum SumCmd {
     Add(i32), Mul(i32),
@@ -29,7 +29,7 @@ impl Cmd for SumCmd {
     }
 }
 
-trait Model: UndoStore<CmdType = SumCmd, ModelType = Sum, ErrType = ()> {
+trait Model: UndoStore<CmdType = SumCmd, ModelType = Sum, ErrType = (), StoreErrType = InMemoryStoreErr> {
     fn add(&mut self, to_add: i32);
     fn mul(&mut self, to_mul: i32);
 }
@@ -65,7 +65,7 @@ impl App {
     fn sum(&self) -> i32 {self.store.model().sum}
     fn call_count(&self) -> usize {self.store.model().call_count}
 
-    fn prompt(&self) -> Vec<Cow<str>> {
+    fn prompt(&self) -> Vec<Cow<'_, str>> {
         vec!(
             format!("Current sum: {}, call count: {}", self.sum(), self.call_count()).into(),
             format!(
@@ -77,12 +77,12 @@ impl App {
     }
 
     fn perform_cmd(&mut self, cmd: &str) -> Resp {
-        if cmd.starts_with("+") {
-            let num: i32 = cmd[1..].trim().parse().unwrap();
+        if let Some(num) = cmd.strip_prefix('+') {
+            let num: i32 = num.trim().parse().unwrap();
             self.store.add(num);
             Resp::Cont
-        } else if cmd.starts_with("*") {
-            let num: i32 = cmd[1..].trim().parse().unwrap();
+        } else if let Some(num) = cmd.strip_prefix('*') {
+            let num: i32 = num.trim().parse().unwrap();
             self.store.mul(num);
             Resp::Cont
         } else if cmd == "u" {
@@ -116,7 +116,7 @@ fn main() {
         }
         io::stdin().read_line(&mut line_buf).unwrap();
         
-        match app.perform_cmd(&line_buf.trim()) {
+        match app.perform_cmd(line_buf.trim()) {
             Resp::Cont => {},
             Resp::Msg(msg) => println!("{}", msg),
             Resp::Quit => break,
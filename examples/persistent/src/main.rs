@@ -1,6 +1,6 @@
 use std::{io, env, borrow::Cow};
 use clap::Parser;
-use serdo::{cmd::{Cmd, SerializableCmd}, undo_store::{Options, SqliteUndoStore, UndoStore}};
+use serdo::{cmd::{Cmd, SerializableCmd}, sqlite_undo_store_error::SqliteUndoStoreError, undo_store::{Options, SqliteUndoStore, UndoStore}};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -95,7 +95,7 @@ enum Resp {
 }
 
 struct App {
-    store: Box<dyn Model<ModelType = Buffer, CmdType = EditorCmd, ErrType = UndoStoreErr>>,
+    store: Box<dyn Model<ModelType = Buffer, CmdType = EditorCmd, ErrType = UndoStoreErr, StoreErrType = SqliteUndoStoreError>>,
 }
 
 impl App {
@@ -111,7 +111,7 @@ impl App {
 
     fn buffer(&self) -> &Vec<String> { &self.store.model().0 }
 
-    fn prompt(&self) -> Vec<Cow<str>> {
+    fn prompt(&self) -> Vec<Cow<'_, str>> {
         let mut buf: Vec<Cow<str>> = vec!("Current buffer:".into());
         for line in self.buffer().iter() {
             buf.push(line.into());
@@ -128,12 +128,12 @@ impl App {
     }
 
     fn perform_cmd(&mut self, cmd: &str) -> Resp {
-        if cmd.starts_with("+") {
-            let txt = cmd[1..].trim();
+        if let Some(txt) = cmd.strip_prefix('+') {
+            let txt = txt.trim();
             self.store.append(txt.to_owned());
             Resp::Cont
-        } else if cmd.starts_with("-") {
-            let loc: usize = cmd[1..].trim().parse().unwrap();
+        } else if let Some(loc) = cmd.strip_prefix('-') {
+            let loc: usize = loc.trim().parse().unwrap();
             match self.store.delete_at(loc) {
                 Err(UndoStoreErr::InvalidIndex { max_index }) => {
                     Resp::Msg(format!("Invalid index max: {}", max_index))
@@ -173,7 +173,7 @@ fn main() {
         }
         io::stdin().read_line(&mut line_buf).unwrap();
         
-        match app.perform_cmd(&line_buf.trim()) {
+        match app.perform_cmd(line_buf.trim()) {
             Resp::Cont => {},
             Resp::Msg(msg) => println!("{}", msg),
             Resp::Quit => break,
@@ -0,0 +1,13 @@
+pub type CipherError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Encrypts commands and snapshots stored by SqliteUndoStore. Implement this with the crypto library of your choice.
+/// Blobs are compressed before encryption.
+pub trait Cipher: Send + Sync {
+    fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, CipherError>;
+
+    /// Should fail if the data is not encrypted with the same key.
+    fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, CipherError>;
+}
+
+// Encrypted and recorded in the database to tell whether the key is correct.
+pub(crate) const KEY_CHECK: &[u8] = b"serdo key check";
@@ -1,13 +1,40 @@
+pub type CmdError = Box<dyn std::error::Error + Send + Sync>;
+
 pub trait Cmd {
     type Model;
 
     fn undo(&self, model: &mut Self::Model);
     fn redo(&self, model: &mut Self::Model);
 
-    // If two commands can be merged, merge them and return the merged command.
-    fn merge<T: Cmd>(&self, _other: &T) -> Option<T> {
+    /// Fallible version of undo() that the stores call. Override this and try_redo() for commands that can fail,
+    /// and implement undo()/redo() by calling them. The model should be left unchanged on failure.
+    fn try_undo(&self, model: &mut Self::Model) -> Result<(), CmdError> {
+        self.undo(model);
+        Ok(())
+    }
+
+    /// Fallible version of redo() that the stores call. See try_undo().
+    fn try_redo(&self, model: &mut Self::Model) -> Result<(), CmdError> {
+        self.redo(model);
+        Ok(())
+    }
+
+    /// If this command and the following `other` command can be merged, return the merged command.
+    /// The merged command will be undone/redone as a single step. Called only for commands added within the merge timeout.
+    fn merge(&self, _other: &Self) -> Option<Self> where Self: Sized {
         None
     }
+
+    /// Label of this command for undo/redo menus such as "Typing" in "Undo Typing". None if not described.
+    fn describe(&self) -> Option<String> {
+        None
+    }
+
+    /// Approximate number of bytes this command holds. Used for the memory budget of InMemoryUndoStore.
+    /// Override this for commands holding heap data such as a pasted text.
+    fn approx_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
 }
 
 #[cfg(feature = "persistence")]
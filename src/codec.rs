@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use bincode::Options;
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serialization format of commands and snapshots stored by SqliteUndoStore.
+/// The name is recorded in the database so that the store is reopened with the same format.
+pub trait Codec: Send + Sync {
+    /// Name recorded in the database. Should be unique among codecs.
+    fn name(&self) -> &str;
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError>;
+
+    /// Pass a deserializer reading the bytes to the visitor.
+    fn deserialize<'de>(
+        &self, bytes: &'de [u8], visitor: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>
+    ) -> Result<(), CodecError>;
+}
+
+impl std::fmt::Debug for dyn Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Codec({})", self.name())
+    }
+}
+
+/// Compact binary format. This is the default and the format used by the stores created before codecs were introduced.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+pub const BINCODE_CODEC_NAME: &str = "bincode";
+
+impl Codec for BincodeCodec {
+    fn name(&self) -> &str {
+        BINCODE_CODEC_NAME
+    }
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn deserialize<'de>(
+        &self, bytes: &'de [u8], visitor: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>
+    ) -> Result<(), CodecError> {
+        // Same options as bincode::deserialize().
+        let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+        let mut de = bincode::Deserializer::from_slice(bytes, options);
+        visitor(&mut <dyn erased_serde::Deserializer>::erase(&mut de))?;
+        Ok(())
+    }
+}
+
+/// Human readable format that is useful to debug the database.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+pub const JSON_CODEC_NAME: &str = "json";
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &str {
+        JSON_CODEC_NAME
+    }
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<'de>(
+        &self, bytes: &'de [u8], visitor: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>
+    ) -> Result<(), CodecError> {
+        let mut de = serde_json::Deserializer::from_slice(bytes);
+        visitor(&mut <dyn erased_serde::Deserializer>::erase(&mut de))?;
+        de.end()?;
+        Ok(())
+    }
+}
+
+/// Returns the built-in codec of the name.
+pub fn builtin_codec(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        BINCODE_CODEC_NAME => Some(Arc::new(BincodeCodec)),
+        JSON_CODEC_NAME => Some(Arc::new(JsonCodec)),
+        _ => None,
+    }
+}
+
+pub(crate) fn serialize<T: serde::Serialize>(codec: &dyn Codec, value: &T) -> Result<Vec<u8>, CodecError> {
+    codec.serialize(value)
+}
+
+pub(crate) fn deserialize<T: serde::de::DeserializeOwned>(codec: &dyn Codec, bytes: &[u8]) -> Result<T, CodecError> {
+    let mut value: Option<T> = None;
+    codec.deserialize(bytes, &mut |de| {
+        value = Some(erased_serde::deserialize(de)?);
+        Ok(())
+    })?;
+    value.ok_or_else(|| format!("Codec {} did not deserialize the value.", codec.name()).into())
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+use crate::sqlite_undo_store_error::SqliteUndoStoreError;
+
+pub const ZLIB_COMPRESSION_NAME: &str = "zlib";
+
+/// Sizes of the commands and snapshots stored in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// Total size before compression.
+    pub raw_size: i64,
+    /// Total size actually stored.
+    pub stored_size: i64,
+}
+
+impl CompressionStats {
+    /// Stored size divided by raw size. 1.0 if nothing is compressed or stored.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_size == 0 {
+            1.0
+        } else {
+            self.stored_size as f64 / self.raw_size as f64
+        }
+    }
+}
+
+// A blob to be stored with its compression. The compression and the raw size are None if not compressed.
+pub(crate) struct Packed {
+    pub data: Vec<u8>,
+    pub compression: Option<&'static str>,
+    pub raw_size: Option<i64>,
+}
+
+// Compress the data if the level is specified. The data is stored as is if compression does not make it smaller.
+pub(crate) fn pack(data: Vec<u8>, level: Option<u32>) -> std::io::Result<Packed> {
+    match level {
+        #[cfg(feature = "compression")]
+        Some(level) => {
+            use std::io::Write;
+
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
+            encoder.write_all(&data)?;
+            let compressed = encoder.finish()?;
+            if compressed.len() < data.len() {
+                Ok(Packed { raw_size: Some(data.len() as i64), data: compressed, compression: Some(ZLIB_COMPRESSION_NAME) })
+            } else {
+                Ok(Packed { data, compression: None, raw_size: None })
+            }
+        }
+        _ => Ok(Packed { data, compression: None, raw_size: None }),
+    }
+}
+
+pub(crate) fn unpack(sqlite_path: &Path, data: Vec<u8>, compression: Option<&str>) -> Result<Vec<u8>, SqliteUndoStoreError> {
+    match compression {
+        None => Ok(data),
+        #[cfg(feature = "compression")]
+        Some(ZLIB_COMPRESSION_NAME) => {
+            use std::io::Read;
+
+            let mut decompressed = Vec::new();
+            flate2::read::ZlibDecoder::new(data.as_slice()).read_to_end(&mut decompressed)
+                .map_err(|e| SqliteUndoStoreError::CompressionError(sqlite_path.to_path_buf(), e))?;
+            Ok(decompressed)
+        }
+        Some(name) => Err(SqliteUndoStoreError::UnsupportedCompression { path: sqlite_path.to_path_buf(), name: name.to_owned() }),
+    }
+}
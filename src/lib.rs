@@ -1,3 +1,9 @@
 pub mod cmd;
 pub mod undo_store;
-pub mod sqlite_undo_store_error;
\ No newline at end of file
+pub mod sqlite_undo_store_error;
+#[cfg(feature = "persistence")]
+pub mod codec;
+#[cfg(feature = "persistence")]
+pub mod compression;
+#[cfg(feature = "persistence")]
+pub mod cipher;
@@ -2,6 +2,9 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "persistence")] {
         use std::{path::PathBuf};
         use error_stack::Report;
+        use crate::codec::CodecError;
+        use crate::cipher::CipherError;
+        use crate::cmd::CmdError;
     }
 }
 
@@ -10,8 +13,10 @@ cfg_if::cfg_if! {
 pub enum SqliteUndoStoreError {
     // Add
     CannotWriteCmd(std::path::PathBuf, std::io::Error),
-    SerializeError(bincode::Error),
+    SerializeError(CodecError),
     NeedCompaction(std::path::PathBuf),
+    // The command and the following operations are not persisted. Reopen the store.
+    PersistFailed { seq_no: i64 },
 
     // Load
     NotFound(std::path::PathBuf),
@@ -20,11 +25,13 @@ pub enum SqliteUndoStoreError {
 
     // Undo/Redo
     CannotUndoRedo,
+    CmdFailed { seq_no: i64, error: CmdError },
 
     // Save as
     CannotCopyStore {
         from: PathBuf, to: PathBuf, error: std::io::Error
     },
+    StoreExists(PathBuf),
 
     // Restore
     CannotRestoreModel {
@@ -34,21 +41,27 @@ pub enum SqliteUndoStoreError {
     // Common
     FileError(PathBuf, std::io::Error),
     NotADirectory(PathBuf),
+    UnsupportedSchemaVersion { path: PathBuf, version: i64, supported: i64 },
     CannotLock { path: std::path::PathBuf, error: std::io::Error },
     CannotUnlock { path: std::path::PathBuf, error: std::io::Error },
-    CannotDeserialize { path: Option<std::path::PathBuf>, seq_no: i64, ser_err: bincode::Error },
+    CannotDeserialize { path: Option<std::path::PathBuf>, seq_no: i64, ser_err: CodecError },
     OrphanSnapshot(PathBuf),
     DbError(std::path::PathBuf, Report<rusqlite::Error>),
     NotOpend,
     AlreadyOpened,
     CmdSequenceError,
-}
-
-#[cfg(feature = "persistence")]
-impl From<bincode::Error> for SqliteUndoStoreError {
-    fn from(err: bincode::Error) -> Self {
-        SqliteUndoStoreError::SerializeError(err)
-    }
+    CannotContactPersister,
+    ReadOnly(PathBuf),
+    UnknownCodec { path: PathBuf, name: String },
+    UnsupportedCompression { path: PathBuf, name: String },
+    CompressionError(PathBuf, std::io::Error),
+    InvalidCompressionLevel(u32),
+    CipherError(PathBuf, CipherError),
+    WrongKey(PathBuf),
+    KeyRequired(PathBuf),
+    NotEncrypted(PathBuf),
+    BranchNotFound(i64),
+    SeqNoOutOfRange(i64),
 }
 
 #[cfg(feature = "persistence")]
@@ -58,13 +71,18 @@ impl std::fmt::Display for SqliteUndoStoreError {
             SqliteUndoStoreError::CannotWriteCmd(path, io_err) => write!(f, "Cannot write command to {:?}: {:?}", path, io_err),
             SqliteUndoStoreError::SerializeError(ser_err) => write!(f, "Cannot serialize {:?}", ser_err),
             SqliteUndoStoreError::NeedCompaction(path) => write!(f, "Need compaction {:?}", path),
+            SqliteUndoStoreError::PersistFailed { seq_no } => write!(f, "Command {} was not persisted. Reopen the store.", seq_no),
             SqliteUndoStoreError::NotFound(path) => write!(f, "Not found {:?}", path),
             SqliteUndoStoreError::CannotReadCmd(path, io_err) => write!(f, "Cannot read cmd {:?}: {:?}", path, io_err),
             SqliteUndoStoreError::DeserializeError(ser_err) => write!(f, "Cannot deserialize {:?}", ser_err),
             SqliteUndoStoreError::CannotUndoRedo => write!(f, "Cannot undo/redo."),
+            SqliteUndoStoreError::CmdFailed { seq_no, error } => write!(f, "Command {} failed: {}", seq_no, error),
             SqliteUndoStoreError::CannotCopyStore { from, to, error } => write!(f, "Cannot copy store from {:?} to {:?}: {:?}", from, to, error),
+            SqliteUndoStoreError::StoreExists(path) => write!(f, "Store already exists {:?}.", path),
             SqliteUndoStoreError::FileError(path, io_err) => write!(f, "File access error {:?}: {:?}", path, io_err),
             SqliteUndoStoreError::NotADirectory(path) => write!(f, "Specified path is not a directory: {:?}.", path),
+            SqliteUndoStoreError::UnsupportedSchemaVersion { path, version, supported } =>
+                write!(f, "Unsupported schema version {} of {:?}. Supported up to {}.", version, path, supported),
             SqliteUndoStoreError::CannotLock { path, error } => write!(f, "Cannot lock: {:?}: {:?}.", path, error),
             SqliteUndoStoreError::CannotUnlock { path, error } => write!(f, "Cannot unlock: {:?}: {:?}.", path, error),
             SqliteUndoStoreError::CannotDeserialize { path, seq_no: id, ser_err } =>
@@ -89,6 +107,18 @@ impl std::fmt::Display for SqliteUndoStoreError {
             SqliteUndoStoreError::NotOpend => write!(f, "Not opend."),
             SqliteUndoStoreError::CmdSequenceError => write!(f, "Command sequence error."),
             SqliteUndoStoreError::AlreadyOpened => write!(f, "Alread opened"),
+            SqliteUndoStoreError::CannotContactPersister => write!(f, "Cannot contact persister server."),
+            SqliteUndoStoreError::ReadOnly(path) => write!(f, "Opened in read-only mode {:?}.", path),
+            SqliteUndoStoreError::UnknownCodec { path, name } => write!(f, "Unknown codec '{}' of {:?}. Specify it by Options::with_codec().", name, path),
+            SqliteUndoStoreError::UnsupportedCompression { path, name } => write!(f, "Unsupported compression '{}' of {:?}. Enable the compression feature.", name, path),
+            SqliteUndoStoreError::CompressionError(path, io_err) => write!(f, "Compression error {:?}: {:?}", path, io_err),
+            SqliteUndoStoreError::InvalidCompressionLevel(level) => write!(f, "Invalid compression level {}. Specify 0-9.", level),
+            SqliteUndoStoreError::CipherError(path, err) => write!(f, "Cipher error {:?}: {:?}", path, err),
+            SqliteUndoStoreError::WrongKey(path) => write!(f, "Wrong key for {:?}.", path),
+            SqliteUndoStoreError::KeyRequired(path) => write!(f, "{:?} is encrypted. Specify the cipher by Options::with_cipher().", path),
+            SqliteUndoStoreError::NotEncrypted(path) => write!(f, "{:?} already has unencrypted records.", path),
+            SqliteUndoStoreError::BranchNotFound(branch_id) => write!(f, "Branch {} not found.", branch_id),
+            SqliteUndoStoreError::SeqNoOutOfRange(seq_no) => write!(f, "Sequence number {} is out of range.", seq_no),
         }
     }
 }
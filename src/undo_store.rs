@@ -1,1603 +1,5785 @@
-use std::time::Duration;
-use crate::cmd::Cmd;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use error_stack::Report;
+use crate::cmd::{Cmd, CmdError};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "persistence")] {
         use std::path::PathBuf;
-        use error_stack::{Report, IntoReport};
+        use error_stack::IntoReport;
         use crate::sqlite_undo_store_error::SqliteUndoStoreError;
         use std::path::{Path};
         use rusqlite::Connection;
         use std::sync::mpsc::Receiver;
         use std::sync::mpsc;
         use std::{sync::mpsc::Sender, thread};
+        use std::sync::Arc;
+        use crate::codec::{self, Codec};
+        use crate::compression::{self, CompressionStats};
+        use crate::cipher::{self, Cipher};
+        use rusqlite::OptionalExtension;
+        use rusqlite::types::Value;
     }
 }
 
+pub type Observer = Box<dyn FnMut(&StoreEvent)>;
+
+#[allow(clippy::type_complexity)]
 pub trait UndoStore {
     type ModelType;
     type CmdType: Cmd<Model = Self::ModelType>;
     type ErrType;
+    /// Error of the store itself such as a persistence failure.
+    type StoreErrType;
 
     fn model(&self) -> &Self::ModelType;
 
-    /// Mutate model and add a command. Returns sequence number of the command. If this is an in-memory store, the sequece number is always zero.
-    fn mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<(), Self::ErrType>;
+    /// Mutate model and add a command. Panics if the store fails. Use try_mutate() to handle store errors.
+    fn mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<(), Self::ErrType> {
+        match self.try_mutate(f) {
+            Ok(result) => result,
+            Err(e) => panic!("Undo store error {:?}.", e),
+        }
+    }
+
+    /// Mutate model and add a command. The outer error is a store error whereas the inner one is returned by the closure.
+    fn try_mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<Result<(), Self::ErrType>, Report<Self::StoreErrType>>;
+
+    /// Same as mutate() but the model is rolled back if the closure fails. Panics if the store fails. Use try_mutate_transactionally() to handle store errors.
+    fn mutate_transactionally(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<(), Self::ErrType>
+        where Self: Sized, Self::ModelType: Clone + 'static
+    {
+        match self.try_mutate_transactionally(f) {
+            Ok(result) => result,
+            Err(e) => panic!("Undo store error {:?}.", e),
+        }
+    }
+
+    /// Same as try_mutate() but the model is restored from the copy taken beforehand if the closure or the store fails,
+    /// so that partial mutation does not remain in the model without a command.
+    fn try_mutate_transactionally(
+        &mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>
+    ) -> Result<Result<(), Self::ErrType>, Report<Self::StoreErrType>> where Self: Sized, Self::ModelType: Clone + 'static {
+        let backup = self.model().clone();
+        let result = self.try_mutate(f);
+        if !matches!(result, Ok(Ok(()))) {
+            self.irreversible_mutate(Box::new(move |model| *model = backup));
+        }
+        result
+    }
 
     /// Mutate a part of model that is out of scope to manage undo/redo operations.
     fn irreversible_mutate<R>(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> R>) -> R where Self: Sized;
 
-    /// Add a command. Returns sequence number of the command. If this is an in-memory store, the sequece number is always zero.
-    fn add_cmd(&mut self, cmd: Self::CmdType);
+    /// Add a command. Panics if the store fails. Use try_add_cmd() to handle store errors.
+    fn add_cmd(&mut self, cmd: Self::CmdType) {
+        if let Err(e) = self.try_add_cmd(cmd) {
+            panic!("Undo store error {:?}.", e);
+        }
+    }
+
+    fn try_add_cmd(&mut self, cmd: Self::CmdType) -> Result<(), Report<Self::StoreErrType>>;
     fn can_undo(&self) -> bool;
-    fn undo(&mut self);
+
+    /// Undo the last command. Panics if the store fails. Use try_undo() to handle store errors.
+    fn undo(&mut self) {
+        if let Err(e) = self.try_undo() {
+            panic!("Undo store error {:?}.", e);
+        }
+    }
+
+    fn try_undo(&mut self) -> Result<(), Report<Self::StoreErrType>>;
     fn can_redo(&self) -> bool;
-    fn redo(&mut self);
+
+    /// Redo the next command. Panics if the store fails. Use try_redo() to handle store errors.
+    fn redo(&mut self) {
+        if let Err(e) = self.try_redo() {
+            panic!("Undo store error {:?}.", e);
+        }
+    }
+
+    fn try_redo(&mut self) -> Result<(), Report<Self::StoreErrType>>;
+
+    /// Current sequence number. It increases by one when a command is added or redone.
+    fn seq_no(&self) -> i64;
+
+    /// Sequence numbers that go_to() accepts.
+    fn seq_no_range(&self) -> std::ops::RangeInclusive<i64>;
+
+    /// Undo/redo commands until the sequence number. Panics if the store fails. Use try_go_to() to handle store errors.
+    fn go_to(&mut self, seq_no: i64) {
+        if let Err(e) = self.try_go_to(seq_no) {
+            panic!("Undo store error {:?}.", e);
+        }
+    }
+
+    fn try_go_to(&mut self, seq_no: i64) -> Result<(), Report<Self::StoreErrType>>;
+
+    /// Commands added until the matching end_group() are undone/redone as a single step. Groups can be nested.
+    /// Undo/redo in a group closes the step so that the following commands make another one.
+    fn begin_group(&mut self);
+
+    fn end_group(&mut self);
+
+    /// Run f in a group. See begin_group().
+    fn group<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R where Self: Sized {
+        self.begin_group();
+        let result = f(self);
+        self.end_group();
+        result
+    }
+
+    /// Description of the command to be undone. A group is described by its first described command. See Cmd::describe().
+    fn undo_description(&self) -> Option<String>;
+
+    /// Description of the command to be redone. A group is described by its first described command. See Cmd::describe().
+    fn redo_description(&self) -> Option<String>;
+
+    /// Mark the current state as clean such as when the document is saved by the user. Panics if the store fails.
+    /// Use try_mark_clean() to handle store errors.
+    fn mark_clean(&mut self) {
+        if let Err(e) = self.try_mark_clean() {
+            panic!("Undo store error {:?}.", e);
+        }
+    }
+
+    fn try_mark_clean(&mut self) -> Result<(), Report<Self::StoreErrType>>;
+
+    /// True if the current state differs from the clean point. Undo/redo back to the clean point makes it clean again.
+    /// A new store is clean. Once the clean point is dropped from the history or its command is merged with another one,
+    /// the store stays dirty until mark_clean() is called.
+    fn is_dirty(&self) -> bool;
+
+    /// Register an observer called after the store changes. See StoreEvent.
+    fn subscribe(&mut self, observer: Observer) -> SubscriptionId;
+
+    /// Returns false if the observer is not registered.
+    fn unsubscribe(&mut self, id: SubscriptionId) -> bool;
 }
 
 #[derive(Debug)]
 pub enum InMemoryStoreErr {
     // Undo/Redo
     CannotUndoRedo,
+    CmdFailed { seq_no: i64, error: CmdError },
+    BranchNotFound(i64),
+    SeqNoOutOfRange(i64),
+
+    // Save/Load
+    #[cfg(feature = "serde")]
+    CannotSave(bincode::Error),
+    #[cfg(feature = "serde")]
+    CannotLoad(bincode::Error),
+    #[cfg(feature = "serde")]
+    UnsupportedFormatVersion { version: u32, supported: u32 },
+}
+
+impl std::fmt::Display for InMemoryStoreErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InMemoryStoreErr::CannotUndoRedo => write!(f, "Cannot undo/redo."),
+            InMemoryStoreErr::CmdFailed { seq_no, error } => write!(f, "Command {} failed: {}", seq_no, error),
+            InMemoryStoreErr::BranchNotFound(branch_id) => write!(f, "Branch {} not found.", branch_id),
+            InMemoryStoreErr::SeqNoOutOfRange(seq_no) => write!(f, "Sequence number {} is out of range.", seq_no),
+            #[cfg(feature = "serde")]
+            InMemoryStoreErr::CannotSave(err) => write!(f, "Cannot save store: {:?}", err),
+            #[cfg(feature = "serde")]
+            InMemoryStoreErr::CannotLoad(err) => write!(f, "Cannot load store: {:?}", err),
+            #[cfg(feature = "serde")]
+            InMemoryStoreErr::UnsupportedFormatVersion { version, supported } =>
+                write!(f, "Unsupported format version {}. Supported up to {}.", version, supported),
+        }
+    }
+}
+
+impl std::error::Error for InMemoryStoreErr {}
+
+/// A redo history kept aside in the undo tree mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchInfo {
+    pub id: i64,
+    /// None if the branch forks from the current history. Otherwise it forks from the parent branch.
+    pub parent_id: Option<i64>,
+    /// Number of commands before the branch in the current history (or the parent branch).
+    pub fork: i64,
+    /// Number of commands in the branch.
+    pub len: usize,
+}
+
+/// A command in the undo/redo history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry<C> {
+    pub seq_no: i64,
+    /// True if the command is undoable, false if it is redoable.
+    pub undoable: bool,
+    /// True if the command is undone/redone together with the previous one. See UndoStore::begin_group().
+    pub grouped: bool,
+    pub cmd: C,
+}
+
+/// When and by whom a command was recorded. See SqliteUndoStore::set_metadata().
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdRecord {
+    pub seq_no: i64,
+    /// None if the command was recorded by an older version of this library.
+    pub created_at: Option<std::time::SystemTime>,
+    pub metadata: Option<Vec<u8>>,
+}
+
+/// A change of the store notified to the observers. See UndoStore::subscribe().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// The command is added. If merged is true, it is merged into the last command of the same sequence number.
+    CmdAdded { seq_no: i64, merged: bool },
+    /// The command is undone. Notified for each command undone by undo in a group or go_to().
+    Undone { seq_no: i64 },
+    /// The command is redone. Notified for each command redone by redo in a group or go_to().
+    Redone { seq_no: i64 },
+    /// The commands are removed from the history. They are either the redo history replaced by a new command or a branch,
+    /// or the oldest commands exceeding the limit.
+    HistoryTruncated { seq_nos: std::ops::RangeInclusive<i64> },
+    /// The model is restored from a snapshot at the sequence number.
+    SnapshotRestored { seq_no: i64 },
+    /// The commands until the sequence number are saved.
+    Saved { seq_no: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+#[derive(Default)]
+struct Observers {
+    last_id: u64,
+    observers: Vec<(SubscriptionId, Observer)>,
+}
+
+impl Observers {
+    fn subscribe(&mut self, observer: Observer) -> SubscriptionId {
+        self.last_id += 1;
+        let id = SubscriptionId(self.last_id);
+        self.observers.push((id, observer));
+        id
+    }
+
+    fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.observers.len();
+        self.observers.retain(|(i, _)| *i != id);
+        self.observers.len() < len
+    }
+
+    fn notify(&mut self, event: StoreEvent) {
+        for (_, observer) in self.observers.iter_mut() {
+            observer(&event);
+        }
+    }
+
+    // Notify the commands undone/redone to move between the sequence numbers.
+    fn notify_moved(&mut self, from: i64, to: i64) {
+        if to < from {
+            for seq_no in (to + 1..=from).rev() {
+                self.notify(StoreEvent::Undone { seq_no });
+            }
+        } else {
+            for seq_no in from + 1..=to {
+                self.notify(StoreEvent::Redone { seq_no });
+            }
+        }
+    }
+}
+
+// Models taken periodically by InMemoryUndoStore. See InMemoryUndoStore::with_snapshot_interval().
+struct Snapshots<M> {
+    interval: usize,
+    clone: fn(&M) -> M,
+    // In ascending order of the sequence number.
+    models: VecDeque<(i64, M)>,
+    // The newest snapshot taken before the oldest command in the history. The commands between are removed, so only the
+    // state at the snapshot itself can be reached.
+    base: Option<(i64, M)>,
+    // The model at the oldest command in the history while the model is at the base.
+    origin: Option<M>,
+}
+
+impl<M> Snapshots<M> {
+    fn nearest(&self, seq_no: i64) -> Option<&(i64, M)> {
+        self.models.iter().min_by_key(|(snapshot_seq_no, _)| (seq_no - snapshot_seq_no).abs())
+    }
+}
+
+// A command with whether it is undone/redone together with the previous one.
+struct Entry<C> {
+    cmd: C,
+    grouped: bool,
+    // Cmd::approx_size() of the command.
+    size: usize,
+}
+
+// Redo history set aside in the undo tree mode. Children fork from the commands of this branch.
+struct Branch<C> {
+    id: i64,
+    fork: usize,
+    cmds: Vec<Entry<C>>,
+    children: Vec<Branch<C>>,
+    // The clean point is after cmds[..clean] if it is in this branch.
+    clean: Option<usize>,
+}
+
+// Forget the clean point kept in the branches when a new one is marked.
+fn clear_branch_clean<C>(branches: &mut [Branch<C>]) {
+    for branch in branches {
+        branch.clean = None;
+        clear_branch_clean(&mut branch.children);
+    }
+}
+
+// Returns ids of the branches from the top level one to the specified one.
+fn branch_path<C>(branches: &[Branch<C>], branch_id: i64) -> Option<Vec<i64>> {
+    for branch in branches {
+        if branch.id == branch_id {
+            return Some(vec![branch_id]);
+        }
+        if let Some(mut path) = branch_path(&branch.children, branch_id) {
+            path.insert(0, branch.id);
+            return Some(path);
+        }
+    }
+    None
+}
+
+// Returns the commands from the fork of the top level branch in the path to the tip of the last one.
+fn branch_path_cmds<'a, C>(mut branches: &'a [Branch<C>], path: &[i64]) -> Vec<&'a C> {
+    let mut cmds = vec![];
+    for (i, id) in path.iter().enumerate() {
+        let Some(branch) = branches.iter().find(|b| b.id == *id) else {
+            break;
+        };
+        // A child forks from its parent relative to the start of the parent.
+        let end = path.get(i + 1).and_then(|child_id| branch.children.iter().find(|b| b.id == *child_id)).map_or(branch.cmds.len(), |child| child.fork);
+        cmds.extend(branch.cmds[..end].iter().map(|entry| &entry.cmd));
+        branches = &branch.children;
+    }
+    cmds
+}
+
+fn collect_branch_info<C>(branches: &[Branch<C>], parent_id: Option<i64>, infos: &mut Vec<BranchInfo>) {
+    for branch in branches {
+        infos.push(BranchInfo { id: branch.id, parent_id, fork: branch.fork as i64, len: branch.cmds.len() });
+        collect_branch_info(&branch.children, Some(branch.id), infos);
+    }
 }
 
 pub struct InMemoryUndoStore<C, M, E> where M: Default {
     phantom: std::marker::PhantomData<E>,
     model: M,
-    store: Vec<C>,
+    // The oldest command is removed in O(1) when the capacity is reached.
+    store: VecDeque<Entry<C>>,
+    capacity: usize,
     location: usize,
+    merge_timeout: Option<Duration>,
+    last_added: Option<Instant>,
+    undo_tree: bool,
+    // Branches forking from the current history.
+    branches: Vec<Branch<C>>,
+    last_branch_id: i64,
+    // Number of commands removed due to the capacity or the memory budget.
+    removed_count: i64,
+    group: GroupState,
+    observers: Observers,
+    // None if the clean point is no longer in the history.
+    clean_seq_no: Option<i64>,
+    memory_budget: Option<usize>,
+    // Total size of the commands in the history.
+    retained_size: usize,
+    snapshots: Option<Snapshots<M>>,
 }
 
 impl<C, M, E> InMemoryUndoStore<C, M, E> where M: Default {
+    /// Keeps up to capacity commands (at least one). The oldest command is removed when a command is added beyond it.
     pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         Self {
             phantom: std::marker::PhantomData,
             model: M::default(),
-            store: Vec::with_capacity(capacity),
+            store: VecDeque::with_capacity(capacity),
+            capacity,
             location: 0,
+            merge_timeout: None,
+            last_added: None,
+            undo_tree: false,
+            branches: vec![],
+            last_branch_id: 0,
+            removed_count: 0,
+            group: GroupState::default(),
+            observers: Observers::default(),
+            clean_seq_no: Some(0),
+            memory_budget: None,
+            retained_size: 0,
+            snapshots: None,
         }
     }
-}
 
-impl<C, M, E> InMemoryUndoStore<C, M, E> where M: Default + 'static, C: Cmd<Model = M> {
-    fn post_cmd(&mut self, cmd: C) {
-        if self.location < self.store.len() {
-            self.store.truncate(self.location);
+    /// Commands added within the timeout after the previous one are merged by Cmd::merge() if possible.
+    pub fn with_merge_timeout(self, timeout: Duration) -> Self {
+        Self {
+            merge_timeout: Some(timeout),
+            ..self
         }
+    }
 
-        while self.store.capacity() <= self.store.len() {
-            self.store.remove(0);
+    /// Keep the redo history as a branch instead of discarding it when a command is added after undo.
+    pub fn with_undo_tree(self) -> Self {
+        Self {
+            undo_tree: true,
+            ..self
         }
-    
-        self.store.push(cmd);
-        self.location = self.store.len();
     }
-}
-
-impl<C, M, E> UndoStore for InMemoryUndoStore<C, M, E>
-    where M: Default + 'static, C: Cmd<Model = M>
-{
-    type ModelType = M;
-    type CmdType = C;
-    type ErrType = E;
 
-    fn mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<(), Self::ErrType> {
-        let result = f(&mut self.model);
-        if let Ok(cmd) = result {
-            self.post_cmd(cmd);
-            Ok(())
-        } else {
-            result.map(|_| ())
+    /// The oldest commands are removed when the total Cmd::approx_size() of the history exceeds the budget in bytes,
+    /// in addition to the capacity. The last command is always kept so that it can be undone. The commands kept in branches
+    /// are not counted until the branch is switched to.
+    pub fn with_memory_budget(self, budget: usize) -> Self {
+        Self {
+            memory_budget: Some(budget),
+            ..self
         }
     }
 
-    fn add_cmd(&mut self, cmd: Self::CmdType) {
-        cmd.redo(&mut self.model);
-        self.post_cmd(cmd);
+    /// Total Cmd::approx_size() of the commands in the history, excluding the ones kept in branches.
+    pub fn retained_size(&self) -> usize {
+        self.retained_size
     }
 
-    #[inline]
-    fn can_undo(&self) -> bool {
-        0 < self.location
+    /// Returns the commands in the history from the oldest one.
+    pub fn history(&self) -> impl Iterator<Item = HistoryEntry<&C>> {
+        let (removed_count, location) = (self.removed_count, self.location);
+        self.store.iter().enumerate().map(move |(i, entry)| HistoryEntry {
+            seq_no: removed_count + i as i64 + 1, undoable: i < location, grouped: entry.grouped, cmd: &entry.cmd,
+        })
     }
 
-    fn undo(&mut self) {
-        if self.can_undo() {
-            self.location -= 1;
-            let cmd = &self.store[self.location];
-            cmd.undo(&mut self.model)
-        }
+    /// Returns all the branches including nested ones in the order of creation.
+    pub fn branches(&self) -> Vec<BranchInfo> {
+        let mut infos = vec![];
+        collect_branch_info(&self.branches, None, &mut infos);
+        infos.sort_by_key(|info| info.id);
+        infos
     }
 
-    #[inline]
-    fn can_redo(&self) -> bool {
-        self.location < self.store.len()
+    fn base_seq_no(&self) -> Option<i64> {
+        self.snapshots.as_ref()?.base.as_ref().map(|(seq_no, _)| *seq_no)
     }
 
-    fn redo(&mut self) {
-        if self.can_redo() {
-            let cmd = &mut self.store[self.location];
-            cmd.redo(&mut self.model);
-            self.location += 1;
-        }
+    fn at_base(&self) -> bool {
+        self.snapshots.as_ref().is_some_and(|snapshots| snapshots.origin.is_some())
     }
 
-    fn model(&self) -> &M {
-        &self.model
+    // The model at the current location of the history, which differs from the model while it is at the base.
+    fn history_model(&self) -> &M {
+        self.snapshots.as_ref().and_then(|snapshots| snapshots.origin.as_ref()).unwrap_or(&self.model)
     }
 
-    fn irreversible_mutate<R>(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> R>) -> R {
-        f(&mut self.model)
+    // Called when the history after the sequence number is discarded.
+    fn forget_states_after(&mut self, seq_no: i64) {
+        if self.clean_seq_no.is_some_and(|clean_seq_no| seq_no < clean_seq_no) {
+            self.clean_seq_no = None;
+        }
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.models.retain(|(snapshot_seq_no, _)| *snapshot_seq_no <= seq_no);
+        }
     }
-}
-
-#[cfg(feature = "persistence")]
-#[derive(Debug)]
-enum PersistCmd {
-    Open { dir: std::path::PathBuf },
-    Close,
-    AddCmd { seq_no: i64, ser_cmd: Vec<u8> },
-    Undo,
-    Redo,
-}
-
-#[cfg(feature = "persistence")]
-#[derive(Debug)]
-enum PersistResp {
-    OpenOk { serialized_model: Vec<u8>, seq_no: i64, min_max_seq_no: Option<(i64, i64)> },
-    OpenErr(Report<SqliteUndoStoreError>),
-
-    CloseOk,
-    #[allow(dead_code)]
-    CloseErr(Report<SqliteUndoStoreError>),
-
-    AddCmdOk { seq_no: i64 },
-    AddCmdErr(Report<SqliteUndoStoreError>),
 
-    UndoOk { seq_no: i64, serialized_command: Vec<u8> },
-    UndoErr(Report<SqliteUndoStoreError>),
-
-    RedoOk { seq_no: i64, serialized_command: Vec<u8> }    ,
-    RedoErr(Report<SqliteUndoStoreError>),
+    // Set the commands after the fork aside as a new branch. Branches forking from them become its children.
+    fn stash_redo_history(&mut self, fork: usize) {
+        if self.store.len() <= fork {
+            return;
+        }
+        let seq_nos = self.removed_count + fork as i64 + 1..=self.removed_count + self.store.len() as i64;
+        // The clean point moves with the branch.
+        let clean = self.clean_seq_no.filter(|seq_no| seq_nos.contains(seq_no)).map(|seq_no| (seq_no - *seq_nos.start() + 1) as usize);
+        self.forget_states_after(*seq_nos.start() - 1);
+        self.observers.notify(StoreEvent::HistoryTruncated { seq_nos });
+        let cmds: Vec<_> = self.store.split_off(fork).into();
+        self.retained_size -= cmds.iter().map(|entry| entry.size).sum::<usize>();
+        let (children, branches): (Vec<_>, Vec<_>) = std::mem::take(&mut self.branches).into_iter().partition(|b| fork < b.fork);
+        self.branches = branches;
+        let children = children.into_iter().map(|mut b| { b.fork -= fork; b }).collect();
+        self.last_branch_id += 1;
+        self.branches.push(Branch { id: self.last_branch_id, fork, cmds, children, clean });
+    }
 }
 
-#[cfg(feature = "persistence")]
-enum PersisterServerState<M>
-    where M: Default + serde::Serialize + serde::de::DeserializeOwned
-{
-    Idle,
-    Loaded {
-        base_dir: std::path::PathBuf,
-        sqlite_path: std::path::PathBuf,
-        cur_cmd_seq_no: i64,
-        model: M,
-        conn: rusqlite::Connection,
+impl<C, M, E> InMemoryUndoStore<C, M, E> where M: Default + Clone {
+    /// Take a snapshot of the model every interval commands. restore_to() restores the nearest snapshot instead of
+    /// undoing/redoing commands one by one. When the oldest commands are removed, the newest snapshot before them is kept
+    /// as the base so that go_to() and undo() can still go back to it. See restore_to() for the states restored.
+    pub fn with_snapshot_interval(self, interval: usize) -> Self {
+        Self {
+            snapshots: Some(Snapshots { interval: interval.max(1), clone: M::clone, models: VecDeque::new(), base: None, origin: None }),
+            ..self
+        }
     }
 }
 
-#[cfg(feature = "persistence")]
-struct PersisterServer<C, M, E>
-  where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
-{
-    phantom: std::marker::PhantomData<C>,
-    phantome: std::marker::PhantomData<E>,
-    receiver: Receiver<PersistCmd>,
-    sender: Sender<PersistResp>,
-    undo_limit: usize,
-    state: PersisterServerState<M>,
+// The file written by InMemoryUndoStore::save_to().
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedStore<C, M> {
+    version: u32,
+    model: M,
+    // Commands from the oldest one with whether each is grouped with the previous one.
+    cmds: Vec<(C, bool)>,
+    location: usize,
+    removed_count: i64,
+    clean_seq_no: Option<i64>,
 }
 
-#[cfg(feature = "persistence")]
-struct PersisterClient {
-    receiver: Receiver<PersistResp>,
-    sender: Sender<PersistCmd>,
-    last_seq_no: i64,
-    last_processed_seq_no: Option<i64>,
-    min_seq_no: Option<i64>,
-    max_seq_no: Option<i64>,
-    undo_limit: usize,
-}
+#[cfg(feature = "serde")]
+pub const SAVED_STORE_VERSION: u32 = 1;
 
-#[cfg(feature = "persistence")]
-impl PersisterClient {
-    fn open(receiver: Receiver<PersistResp>, sender: Sender<PersistCmd>, dir: PathBuf, undo_limit: usize)
-       -> Result<(Self, Vec<u8>), Report<SqliteUndoStoreError>> 
+#[cfg(feature = "serde")]
+impl<C, M, E> InMemoryUndoStore<C, M, E> where M: Default, C: Cmd<Model = M> {
+    /// Write the model, the history and the clean point. Branches and snapshots are not saved. If the model is at the base
+    /// snapshot, the store is saved at the oldest command instead.
+    pub fn save_to<W: std::io::Write>(&mut self, writer: W) -> Result<(), Report<InMemoryStoreErr>>
+        where C: serde::Serialize, M: serde::Serialize
     {
-        sender.send(PersistCmd::Open { dir }).unwrap();
-        let msg = receiver.recv().unwrap();
-        let (serialized_model, seq_no, min_max_seq_no) = match msg {
-            PersistResp::OpenOk { serialized_model, seq_no, min_max_seq_no } =>
-                (serialized_model, seq_no, min_max_seq_no),
-            PersistResp::OpenErr(report) => return Err(report),
-            PersistResp::CloseOk => {
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            },
-            PersistResp::CloseErr(report) => {
-                println!("Unexpected close error {:?}", report);
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
-            PersistResp::AddCmdOk { seq_no: _ } => {
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
-            PersistResp::AddCmdErr(err) => {
-                return Err(err);
-            }
-            PersistResp::UndoOk { seq_no: _, serialized_command: _ } => {
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
-            PersistResp::UndoErr(report) => {
-                println!("Unexpected undo error {:?}", report);
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
-            PersistResp::RedoOk { seq_no: _, serialized_command: _ } => {
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
-            PersistResp::RedoErr(report) => {
-                println!("Unexpected redo error {:?}", report);
-                return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-            }
+        let saved = SavedStore {
+            version: SAVED_STORE_VERSION,
+            model: self.history_model(),
+            cmds: self.store.iter().map(|entry| (&entry.cmd, entry.grouped)).collect(),
+            location: self.location,
+            removed_count: self.removed_count,
+            clean_seq_no: self.clean_seq_no,
         };
-        let (min_seq_no, max_seq_no) = if let Some((min, max)) = min_max_seq_no {
-            (Some(min), Some(max))
-        } else { (None, None) };
+        bincode::serialize_into(writer, &saved).map_err(|e| Report::new(InMemoryStoreErr::CannotSave(e)))?;
+        self.observers.notify(StoreEvent::Saved { seq_no: self.removed_count + self.store.len() as i64 });
+        Ok(())
+    }
 
-        Ok((Self { receiver, sender, last_seq_no: seq_no, min_seq_no, max_seq_no, last_processed_seq_no: None, undo_limit }, serialized_model))
+    /// Replace the model and the history with the ones written by save_to(). The options of this store are kept.
+    /// If the saved history exceeds the capacity, the oldest commands are removed first, then the redo history.
+    pub fn load_from<R: std::io::Read>(&mut self, reader: R) -> Result<(), Report<InMemoryStoreErr>>
+        where C: serde::de::DeserializeOwned, M: serde::de::DeserializeOwned
+    {
+        let saved: SavedStore<C, M> = bincode::deserialize_from(reader).map_err(|e| Report::new(InMemoryStoreErr::CannotLoad(e)))?;
+        if saved.version != SAVED_STORE_VERSION {
+            error_stack::bail!(InMemoryStoreErr::UnsupportedFormatVersion { version: saved.version, supported: SAVED_STORE_VERSION });
+        }
+        self.replace_history(saved.model, saved.cmds, saved.location, saved.removed_count, saved.clean_seq_no);
+        Ok(())
     }
 
-    fn undo(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        self.post_cmd(PersistCmd::Undo);
-        let resp = self.wait_undo_resp();
-        if let Ok(r) = &resp {
-            let seq_no = r.0;
-            if seq_no != self.last_seq_no {
-                println!("Unexpected sequence number: {} != {}", seq_no, self.last_seq_no);
-                error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+    // Replace the model and the history keeping the options. The commands beyond the capacity are removed from the oldest one
+    // before the location, then from the newest one.
+    fn replace_history(&mut self, model: M, cmds: Vec<(C, bool)>, location: usize, removed_count: i64, clean_seq_no: Option<i64>) {
+        let mut store: VecDeque<_> = cmds.into_iter().map(|(cmd, grouped)| {
+            let size = cmd.approx_size();
+            Entry { cmd, grouped, size }
+        }).collect();
+        let (mut location, mut removed_count) = (location.min(store.len()), removed_count);
+        while self.capacity < store.len() {
+            if 0 < location {
+                store.pop_front();
+                location -= 1;
+                removed_count += 1;
+            } else {
+                store.pop_back();
             }
         }
 
-        self.last_seq_no -= 1;
-        resp
+        self.model = model;
+        self.retained_size = store.iter().map(|entry| entry.size).sum();
+        self.store = store;
+        self.location = location;
+        self.removed_count = removed_count;
+        self.clean_seq_no = clean_seq_no
+            .filter(|seq_no| (removed_count..=removed_count + self.store.len() as i64).contains(seq_no));
+        self.last_added = None;
+        self.group = GroupState::default();
+        self.branches.clear();
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.models.clear();
+            snapshots.base = None;
+            snapshots.origin = None;
+        }
+        self.observers.notify(StoreEvent::SnapshotRestored { seq_no: removed_count + location as i64 });
     }
+}
 
-    fn redo(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        self.post_cmd(PersistCmd::Redo);
-        let resp = self.wait_redo_resp();
+impl<C, M, E> InMemoryUndoStore<C, M, E> where M: Default + 'static, C: Cmd<Model = M> {
+    fn post_cmd(&mut self, cmd: C) {
+        self.truncate_at_base();
+        let now = Instant::now();
+        let last_added = self.last_added.replace(now);
+        if self.location == self.store.len() && is_within_merge_timeout(self.merge_timeout, last_added, now) {
+            if let Some(last) = self.store.back_mut() {
+                if let Some(merged) = last.cmd.merge(&cmd) {
+                    let size = merged.approx_size();
+                    self.retained_size = self.retained_size - last.size + size;
+                    last.cmd = merged;
+                    last.size = size;
+                    let seq_no = self.seq_no();
+                    if self.clean_seq_no == Some(seq_no) {
+                        self.clean_seq_no = None;
+                    }
+                    // The snapshot taken just after the replaced command is no longer valid.
+                    if let Some(snapshots) = &mut self.snapshots {
+                        if let Some((_, model)) = snapshots.models.back_mut().filter(|(snapshot_seq_no, _)| *snapshot_seq_no == seq_no) {
+                            *model = (snapshots.clone)(&self.model);
+                        }
+                    }
+                    self.observers.notify(StoreEvent::CmdAdded { seq_no, merged: true });
+                    self.enforce_memory_budget();
+                    return;
+                }
+            }
+        }
 
-        if let Ok((seq_no, _)) = &resp {
-            if *seq_no != self.last_seq_no {
-                println!("Unexpected sequence number: {} != {}", seq_no, self.last_seq_no);
-                error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+        if self.location < self.store.len() {
+            if self.undo_tree {
+                self.stash_redo_history(self.location);
+            } else {
+                let seq_nos = self.seq_no() + 1..=self.removed_count + self.store.len() as i64;
+                self.retained_size -= self.store.range(self.location..).map(|entry| entry.size).sum::<usize>();
+                self.store.truncate(self.location);
+                self.forget_states_after(self.seq_no());
+                self.observers.notify(StoreEvent::HistoryTruncated { seq_nos });
             }
         }
 
-        self.last_seq_no += 1;
-        resp
+        while self.capacity <= self.store.len() {
+            self.remove_oldest();
+        }
+    
+        let grouped = self.group.add_cmd();
+        let size = cmd.approx_size();
+        self.store.push_back(Entry { cmd, grouped, size });
+        self.retained_size += size;
+        self.location = self.store.len();
+        let seq_no = self.seq_no();
+        if let Some(snapshots) = &mut self.snapshots {
+            if seq_no % snapshots.interval as i64 == 0 {
+                snapshots.models.push_back((seq_no, (snapshots.clone)(&self.model)));
+            }
+        }
+        self.observers.notify(StoreEvent::CmdAdded { seq_no, merged: false });
+        self.enforce_memory_budget();
     }
 
-    fn post_cmd(&self, cmd: PersistCmd) {
-        self.sender.send(cmd).unwrap();
+    // The last command is kept even if it exceeds the budget alone so that it can be undone.
+    fn enforce_memory_budget(&mut self) {
+        while 1 < self.store.len() && self.memory_budget.is_some_and(|budget| budget < self.retained_size) {
+            self.remove_oldest();
+        }
     }
 
-    fn add_command(&mut self, ser_cmd: Vec<u8>) {
-        self.post_cmd(PersistCmd::AddCmd { seq_no: self.last_seq_no, ser_cmd });
-        self.last_seq_no += 1;
-        match self.min_seq_no {
-            Some(min_seq_no) => {
-                if min_seq_no + (self.undo_limit as i64) <= self.last_seq_no {
-                    self.min_seq_no = Some(self.last_seq_no - (self.undo_limit as i64) + 1);
-                }
+    // Should be called while the location is at the end of the history.
+    fn remove_oldest(&mut self) {
+        let Some(entry) = self.store.pop_front() else {
+            return;
+        };
+        self.retained_size -= entry.size;
+        self.location -= 1;
+        self.removed_count += 1;
+        // Branches forking before the removed command cannot be reached anymore.
+        self.branches.retain_mut(|b| if b.fork == 0 { false } else { b.fork -= 1; true });
+        let seq_no = self.removed_count;
+        // The newest snapshot before the history is kept as the base.
+        if let Some(snapshots) = &mut self.snapshots {
+            while snapshots.models.front().is_some_and(|(snapshot_seq_no, _)| *snapshot_seq_no < seq_no) {
+                snapshots.base = snapshots.models.pop_front();
             }
-            None => self.min_seq_no = Some(self.last_seq_no),
         }
+        // The state before the removed command cannot be reached anymore unless it is the base.
+        if self.clean_seq_no.is_some_and(|clean_seq_no| clean_seq_no < seq_no && Some(clean_seq_no) != self.base_seq_no()) {
+            self.clean_seq_no = None;
+        }
+        self.observers.notify(StoreEvent::HistoryTruncated { seq_nos: seq_no..=seq_no });
     }
 
-    fn process_resp(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
-        loop {
-            match self.receiver.recv_timeout(Duration::ZERO) {
-                Ok(resp) => match resp {
-                    PersistResp::OpenOk { serialized_model: _, seq_no: _, min_max_seq_no: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::OpenErr(err) => {
-                        println!("Open error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::CloseOk => return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::CloseErr(err) => {
-                        println!("Close error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::AddCmdOk { seq_no } => {
-                        self.last_processed_seq_no = Some(seq_no);
-                        self.max_seq_no = Some(seq_no);
-                    }
-                    PersistResp::AddCmdErr(err) => {
-                        return Err(err);
-                    }
-                    PersistResp::UndoOk { seq_no: _, serialized_command: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::UndoErr(err) => {
-                        println!("Undo error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::RedoOk { seq_no: _, serialized_command: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::RedoErr(err) => {
-                        println!("Redo error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                }
-                Err(_) => break,
-            }
+    fn undo_step(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        let seq_no = self.seq_no();
+        self.store[self.location - 1].cmd.try_undo(&mut self.model).map_err(|error| Report::new(InMemoryStoreErr::CmdFailed { seq_no, error }))?;
+        self.location -= 1;
+        Ok(())
+    }
+
+    fn redo_step(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        let seq_no = self.seq_no() + 1;
+        self.store[self.location].cmd.try_redo(&mut self.model).map_err(|error| Report::new(InMemoryStoreErr::CmdFailed { seq_no, error }))?;
+        self.location += 1;
+        Ok(())
+    }
+
+    fn step_to(&mut self, location: usize) -> Result<(), Report<InMemoryStoreErr>> {
+        while location < self.location {
+            self.undo_step()?;
+        }
+        while self.location < location {
+            self.redo_step()?;
         }
         Ok(())
     }
 
-    fn wait_undo_resp(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        loop {
-            match self.receiver.recv() {
-                Ok(resp) => match resp {
-                    PersistResp::OpenOk { serialized_model: _, seq_no: _, min_max_seq_no: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::OpenErr(err) => {
-                        println!("Open error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::CloseOk => return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::CloseErr(err) => {
-                        println!("Close error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::AddCmdOk { seq_no } => {
-                        self.last_processed_seq_no = Some(seq_no);
-                    }
-                    PersistResp::AddCmdErr(err) => {
-                        return Err(err);
-                    }
-                    PersistResp::UndoOk { seq_no, serialized_command } => return Ok((seq_no, serialized_command)),
-                    PersistResp::UndoErr(err) => return Err(err),
-                    PersistResp::RedoOk { seq_no: _, serialized_command: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::RedoErr(err) => {
-                        println!("Redo error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                }
-                Err(err) => {
-                    println!("Fail to communicate persister server: {:?}", err);
-                }
+    // Undo/redo commands until the location. Moves back to the original location if a command fails.
+    fn move_to(&mut self, location: usize) -> Result<(), Report<InMemoryStoreErr>> {
+        self.last_added = None;
+        self.group.has_cmd = false;
+        let base_model = self.leave_base();
+        let start = self.location;
+        let result = self.step_to(location);
+        if result.is_err() {
+            // Best effort.
+            let _ = self.step_to(start);
+            if let Some(model) = base_model {
+                self.enter_base(model);
             }
+        } else {
+            self.observers.notify_moved(self.removed_count + start as i64, self.seq_no());
         }
+        result
     }
 
-    fn wait_redo_resp(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        loop {
-            match self.receiver.recv() {
-                Ok(resp) => match resp {
-                    PersistResp::OpenOk { serialized_model: _, seq_no: _, min_max_seq_no: _ } =>
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::OpenErr(err) => {
-                        println!("Open error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::CloseOk => return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::CloseErr(err) => {
-                        println!("Close error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::AddCmdOk { seq_no } => {
-                        self.last_processed_seq_no = Some(seq_no);
-                    }
-                    PersistResp::AddCmdErr(err) => {
-                        return Err(err);
-                    }
-                    PersistResp::UndoOk { seq_no: _, serialized_command: _ } => 
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report()),
-                    PersistResp::UndoErr(err) => {
-                        println!("Undo error {:?}", err);
-                        return Err(SqliteUndoStoreError::CmdSequenceError.into_report());
-                    }
-                    PersistResp::RedoOk { seq_no, serialized_command } => return Ok((seq_no, serialized_command)),
-                    PersistResp::RedoErr(err) => return Err(err),
-                }
-                Err(err) => {
-                    println!("Fail to communicate persister server: {:?}", err);
-                }
+    // Restore the nearest snapshot in the history and undo/redo the rest. Moves back to the original location if a command fails.
+    fn jump_to(&mut self, location: usize) -> Result<(), Report<InMemoryStoreErr>> {
+        let target = self.removed_count + location as i64;
+        let Some((seq_no, model)) = self.snapshots.as_ref().and_then(|snapshots| {
+            snapshots.nearest(target).map(|(seq_no, model)| (*seq_no, (snapshots.clone)(model)))
+        }) else {
+            return self.move_to(location);
+        };
+        let origin = self.snapshots.as_mut().and_then(|snapshots| snapshots.origin.take());
+        let backup = std::mem::replace(&mut self.model, model);
+        let start = self.location;
+        self.last_added = None;
+        self.group.has_cmd = false;
+        self.location = (seq_no - self.removed_count) as usize;
+        let result = self.step_to(location);
+        if result.is_err() {
+            self.model = backup;
+            self.location = start;
+            if let Some(snapshots) = &mut self.snapshots {
+                snapshots.origin = origin;
             }
+        } else {
+            self.observers.notify(StoreEvent::SnapshotRestored { seq_no });
+            self.observers.notify_moved(seq_no, target);
         }
+        result
     }
 
-    fn can_undo(&self) -> bool {
-        if let Some(min_seq_no) = self.min_seq_no {
-            min_seq_no <= self.last_seq_no
-        } else { false }
+    // Undo the commands to the oldest one, then restore the base snapshot.
+    fn move_to_base(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        if self.at_base() {
+            return Ok(());
+        }
+        self.move_to(0)?;
+        let Some(model) = self.snapshots.as_ref().and_then(|snapshots| snapshots.base.as_ref().map(|(_, model)| (snapshots.clone)(model))) else {
+            return Ok(());
+        };
+        self.enter_base(model);
+        Ok(())
     }
 
-    fn can_redo(&self) -> bool {
-        if let Some(max_seq_no) = self.max_seq_no {
-            self.last_seq_no < max_seq_no
-        } else { false }
+    // Should be called at the oldest command.
+    fn enter_base(&mut self, model: M) {
+        let Some(snapshots) = &mut self.snapshots else {
+            return;
+        };
+        snapshots.origin = Some(std::mem::replace(&mut self.model, model));
+        if let Some(seq_no) = self.base_seq_no() {
+            self.observers.notify(StoreEvent::SnapshotRestored { seq_no });
+        }
     }
 
-    fn saved(&self) -> bool {
-        if let Some(proceesed_seq_no) = self.last_processed_seq_no {
-            proceesed_seq_no == self.last_seq_no
-        } else { false }
+    // Move from the base to the oldest command. Returns the model at the base.
+    fn leave_base(&mut self) -> Option<M> {
+        let origin = self.snapshots.as_mut()?.origin.take()?;
+        let model = std::mem::replace(&mut self.model, origin);
+        self.observers.notify(StoreEvent::SnapshotRestored { seq_no: self.removed_count });
+        Some(model)
     }
-}
 
-#[cfg(feature = "persistence")]
-impl Drop for PersisterClient {
-    fn drop(&mut self) {
-        self.sender.send(PersistCmd::Close).unwrap();
-        loop {
-            match self.receiver.recv().unwrap() {
-                PersistResp::OpenOk { serialized_model: _, seq_no: _, min_max_seq_no: _ } => {
-                    println!("Unexpected open.");
-                }
-                PersistResp::OpenErr(e) => {
-                    println!("Unexpeced open error: {:?}", e);
-                },
-                PersistResp::CloseOk => {
-                    break;
-                }
-                PersistResp::CloseErr(err) => {
-                    println!("Unexpeced close error: {:?}", err);
-                    break;
-                }
-                PersistResp::AddCmdOk { seq_no: _ } => {
-                }
-                PersistResp::AddCmdErr(err) => {
-                    println!("Add command error: {:?}", err);
-                }
-                PersistResp::UndoOk { seq_no: _, serialized_command: _ } => {}
-                PersistResp::UndoErr(err) => {
-                    println!("Undo error: {:?}", err);
-                }
-                PersistResp::RedoOk { seq_no: _, serialized_command: _ } => {}
-                PersistResp::RedoErr(err) => {
-                    println!("Redo error: {:?}", err);
-                }
+    // A command added at the base discards the history, which cannot be reached from the base by redoing commands.
+    fn truncate_at_base(&mut self) {
+        let Some(snapshots) = self.snapshots.as_mut().filter(|snapshots| snapshots.origin.is_some()) else {
+            return;
+        };
+        snapshots.origin = None;
+        let Some((seq_no, model)) = snapshots.base.take() else {
+            return;
+        };
+        let seq_nos = seq_no + 1..=self.removed_count + self.store.len() as i64;
+        self.forget_states_after(seq_no);
+        self.observers.notify(StoreEvent::HistoryTruncated { seq_nos });
+        self.store.clear();
+        self.retained_size = 0;
+        self.branches.clear();
+        self.location = 0;
+        self.removed_count = seq_no;
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.models.push_front((seq_no, model));
+        }
+    }
+
+    /// Move to the tip of the branch. The current redo history becomes a new branch.
+    pub fn switch_branch(&mut self, branch_id: i64) -> Result<(), Report<InMemoryStoreErr>> {
+        let path = branch_path(&self.branches, branch_id).ok_or_else(|| Report::new(InMemoryStoreErr::BranchNotFound(branch_id)))?;
+        let start = self.seq_no();
+        let fork = self.branches.iter().find(|b| b.id == path[0]).map_or(0, |b| b.fork);
+        self.try_go_to(self.removed_count + fork as i64)?;
+
+        // Redo the commands to the tip before touching the history so that a failed command leaves the store unchanged.
+        let cmds = branch_path_cmds(&self.branches, &path);
+        let failed = cmds.iter().enumerate().find_map(|(i, cmd)| cmd.try_redo(&mut self.model).err().map(|error| (i, error)));
+        if let Some((i, error)) = failed {
+            // Best effort.
+            for cmd in cmds[..i].iter().rev() {
+                let _ = cmd.try_undo(&mut self.model);
             }
+            let _ = self.try_go_to(start);
+            return Err(Report::new(InMemoryStoreErr::CmdFailed { seq_no: self.removed_count + (fork + i + 1) as i64, error }));
         }
+
+        // Each branch in the path forks from the current history after switching to its parent.
+        for id in path {
+            let fork = self.branches.iter().find(|b| b.id == id).map_or(0, |b| b.fork);
+            self.stash_redo_history(fork);
+            let Some(pos) = self.branches.iter().position(|b| b.id == id) else {
+                continue;
+            };
+            let branch = self.branches.remove(pos);
+            if let Some(clean) = branch.clean {
+                self.clean_seq_no = Some(self.removed_count + (fork + clean) as i64);
+            }
+            self.retained_size += branch.cmds.iter().map(|entry| entry.size).sum::<usize>();
+            self.store.extend(branch.cmds);
+            self.branches.extend(branch.children.into_iter().map(|mut b| { b.fork += fork; b }));
+        }
+        self.location = self.store.len();
+        self.observers.notify_moved(self.removed_count + fork as i64, self.seq_no());
+        self.enforce_memory_budget();
+        Ok(())
     }
-}
 
-#[cfg(feature = "persistence")]
-macro_rules! send {
-    ($sender:expr, $msg:expr) => {
-        tracing::trace!("Persister server response: {:?}", $msg);
-        $sender.send($msg).unwrap();
-    };
+    /// Move to the sequence number by restoring the nearest snapshot and undoing/redoing the rest. Unlike go_to(), this always
+    /// restores the states out of scope of undo/redo to the ones at the snapshot. Same as go_to() if there is no snapshot in
+    /// the history. See with_snapshot_interval().
+    pub fn restore_to(&mut self, seq_no: i64) -> Result<(), Report<InMemoryStoreErr>> {
+        if self.base_seq_no() == Some(seq_no) {
+            return self.move_to_base();
+        }
+        if !(self.removed_count..=self.removed_count + self.store.len() as i64).contains(&seq_no) {
+            return Err(Report::new(InMemoryStoreErr::SeqNoOutOfRange(seq_no)));
+        }
+        self.jump_to((seq_no - self.removed_count) as usize)
+    }
 }
 
-
-#[cfg(feature = "persistence")]
-impl<C, M, E> PersisterServer<C, M, E>
-    where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
+impl<C, M, E> UndoStore for InMemoryUndoStore<C, M, E>
+    where M: Default + 'static, C: Cmd<Model = M>
 {
-    fn new(
-        receiver: Receiver<PersistCmd>,
-        sender: Sender<PersistResp>,
-        undo_limit: usize,
-        _merge_timeout: Option<Duration>,
-    ) -> Self {
-        Self {
-            undo_limit,
-            phantom: std::marker::PhantomData, phantome: std::marker::PhantomData,
-            receiver, sender, state: PersisterServerState::Idle
+    type ModelType = M;
+    type CmdType = C;
+    type ErrType = E;
+    type StoreErrType = InMemoryStoreErr;
+
+    fn try_mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<Result<(), Self::ErrType>, Report<InMemoryStoreErr>> {
+        let result = f(&mut self.model);
+        if let Ok(cmd) = result {
+            self.post_cmd(cmd);
+            Ok(Ok(()))
+        } else {
+            Ok(result.map(|_| ()))
         }
     }
 
-    fn start(mut self) {
-        loop {
-            let msg = self.receiver.recv();
-            tracing::trace!("PersisterServer received msg: {:?}", msg);
-            match msg {
-                Ok(cmd) => {
-                    match cmd {
-                        PersistCmd::Open { dir } => {
-                            if let PersisterServerState::Loaded { base_dir: _, sqlite_path: _, cur_cmd_seq_no: _, model: _, conn: _ } = &self.state {
-                                tracing::error!("Already opend.");
-                                send!(self.sender, PersistResp::OpenErr(SqliteUndoStoreError::AlreadyOpened.into_report()));
-                            }
+    fn try_add_cmd(&mut self, cmd: Self::CmdType) -> Result<(), Report<InMemoryStoreErr>> {
+        let seq_no = self.seq_no() + 1;
+        cmd.try_redo(&mut self.model).map_err(|error| Report::new(InMemoryStoreErr::CmdFailed { seq_no, error }))?;
+        self.post_cmd(cmd);
+        Ok(())
+    }
 
-                            let mut sqlite_path = dir.clone();
-                            sqlite_path.push(SQLITE_FILE_NAME);
-                            match Self::open_sqlite(dir.clone(), sqlite_path.clone()) {
-                                Ok(conn) => {
-                                    tracing::trace!("Succeed to open sqlite file: {:?}", sqlite_path);
-                                    let db = Db::new(sqlite_path.clone(), &conn);
-                                    match Self::restore_model(&sqlite_path, &conn) {
-                                        Ok((cur_cmd_seq_no, model)) => {
-                                            tracing::trace!("Succeed to restore model(seq: {})", cur_cmd_seq_no);
-
-                                            match bincode::serialize(&model) {
-                                                Ok(sm) => {
-                                                    match Self::min_max_seq_no(&db) {
-                                                        Ok(min_max_seq_no) => {
-                                                            tracing::trace!("Min/Max: {:?}", min_max_seq_no);
-                                                            let msg = PersistResp::OpenOk { serialized_model: sm, seq_no: cur_cmd_seq_no, min_max_seq_no };
-                                                            send!(self.sender, msg);
-                                                            self.state = PersisterServerState::Loaded {
-                                                                base_dir: dir, sqlite_path, cur_cmd_seq_no, model, conn
-                                                            };
-                                                        }
-                                                        Err(err) => {
-                                                            tracing::error!("Cannot retrieve min/max seq {:?}", err);
-                                                            let msg = PersistResp::OpenErr(err.into_report());
-                                                            send!(self.sender, msg);
-                                                        }
-                                                    };
-                                                },
-                                                Err(err) => {
-                                                    tracing::error!("Cannot serialize model: {:?}", err);
-                                                    let msg = PersistResp::OpenErr(
-                                                        SqliteUndoStoreError::CannotDeserialize {
-                                                            path: Some(sqlite_path.clone()), seq_no: cur_cmd_seq_no, ser_err: err
-                                                        }.into_report()
-                                                    );
-                                                    send!(self.sender, msg);
-                                                }
-                                            };
-                                        }
-                                        Err(err) => {
-                                            tracing::error!("Cannot restore model: {:?}", err);
-                                            let msg = PersistResp::OpenErr(err);
-                                            send!(self.sender, msg);
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    tracing::error!("Cannot open sqlite(path={:?}): {:?}", sqlite_path, err);
-                                    let msg = PersistResp::OpenErr(err);
-                                    send!(self.sender, msg);
-                                }
-                            }
-                        }
-                        PersistCmd::Close => {
-                            match &self.state {
-                                PersisterServerState::Idle => {
-                                    tracing::trace!("Already closed.");
-                                    // Already closed.
-                                    send!(self.sender, PersistResp::CloseOk);
-                                    break;
-                                }
-                                PersisterServerState::Loaded { base_dir, sqlite_path: _, cur_cmd_seq_no: _, model: _, conn: _ } => {
-                                    Self::unlock(base_dir).unwrap();
-                                    send!(self.sender, PersistResp::CloseOk);
-                                    break;
-                                }
-                            }                        
-                        }
-                        PersistCmd::AddCmd { seq_no, ser_cmd } => {
-                            match self.add_cmd(seq_no, ser_cmd) {
-                                Ok(_) => {
-                                    tracing::trace!("Cmd add ok");
-                                    let seq_no = seq_no + 1;
-                                    if let PersisterServerState::Loaded { base_dir: _, conn: _, cur_cmd_seq_no, sqlite_path: _, model: _ } = &mut self.state {
-                                        *cur_cmd_seq_no = seq_no;
-                                    }
-                                    send!(self.sender, PersistResp::AddCmdOk { seq_no });
-                                },
-                                Err(err) => {
-                                    tracing::error!("Add cmd error {:?}", err);
-                                    let msg = PersistResp::AddCmdErr(err);
-                                    send!(self.sender, msg);
-                                }
-                            }
-                        }
-                        PersistCmd::Undo => {
-                            match self.undo() {
-                                Ok((seq_no, serialized_command)) => {
-                                    tracing::trace!("Undo ok seq:{}", seq_no);
-                                    let msg = PersistResp::UndoOk { seq_no, serialized_command };
-                                    send!(self.sender, msg);
-                                }
-                                Err(err) => {
-                                    tracing::error!("Undo err {:?}", err);
-                                    let msg = PersistResp::UndoErr(err);
-                                    send!(self.sender, msg);
-                                }
-                            }
-                        }
-                        PersistCmd::Redo => {
-                            match self.redo() {
-                                Ok((seq_no, serialized_command)) => {
-                                    tracing::trace!("Redo ok seq:{}", seq_no);
-                                    let msg = PersistResp::RedoOk { seq_no, serialized_command };
-                                    send!(self.sender, msg);
-                                }
-                                Err(err) => {
-                                    tracing::error!("Redo err {:?}", err);
-                                    let msg = PersistResp::RedoErr(err);
-                                    send!(self.sender, msg);
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    tracing::error!("Persister server cannot contact the client {:?}", err);
-                    break;
-                }
+    #[inline]
+    fn can_undo(&self) -> bool {
+        0 < self.location || (self.base_seq_no().is_some() && !self.at_base())
+    }
+
+    // Commands in the group are undone together. Undo at the oldest command restores the base snapshot.
+    fn try_undo(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        if 0 < self.location {
+            let mut location = self.location - 1;
+            while 0 < location && self.store[location].grouped {
+                location -= 1;
             }
+            self.move_to(location)?;
+        } else if self.can_undo() {
+            self.move_to_base()?;
         }
+        Ok(())
     }
 
-    fn min_max_seq_no(db: &Db) -> Result<Option<(i64, i64)>, Report<SqliteUndoStoreError>> {
-        db.exec(|conn| {
-            let mut stmt = conn.prepare(
-                "select count(command_id), min(command_id), max(command_id) from command"
-            )?;
-            let mut rows = stmt.query([])?;
-            let row = rows.next()?.unwrap();
-            let count: i64 = row.get(0)?;
-            Ok(
-                if count == 0 {
-                    None
-                } else {
-                    let min: i64 = row.get(1)?;
-                    let max: i64 = row.get(2)?;
-                    Some((min, max))
-                }
-            )
-        })
+    #[inline]
+    fn can_redo(&self) -> bool {
+        self.at_base() || self.location < self.store.len()
     }
 
-    fn undo(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        match &mut self.state {
-            PersisterServerState::Idle => {
-                send!(self.sender, PersistResp::UndoErr(SqliteUndoStoreError::NotOpend.into_report()));
-                Err(SqliteUndoStoreError::NotOpend.into_report())
-            }
-            PersisterServerState::Loaded { base_dir: _, sqlite_path, cur_cmd_seq_no, model, conn } => {
-                let db = Db::new(sqlite_path.clone(), conn);
-                let ser_cmd: Option<Vec<u8>> = db.exec(|conn| {
-                    let mut stmt = conn.prepare(
-                        "select serialized from command where command_id = ?1"
-                    )?;
-                    let mut rows = stmt.query(rusqlite::params![*cur_cmd_seq_no])?;
-                    let ser_cmd = match rows.next()? {
-                        Some(row) => {
-                            let ser: Vec<u8> = row.get(0)?;
-                            Some(ser)
-                        },
-                        None => None,
-                    };
-                    Ok(ser_cmd)
-                })?;
-                if let Some(ser_cmd) = ser_cmd {
-                    let cmd: C = bincode::deserialize(&ser_cmd).map_err(|ser_err|
-                        SqliteUndoStoreError::CannotDeserialize {
-                            path: Some(sqlite_path.clone()), seq_no: *cur_cmd_seq_no, ser_err
-                        }
-                    )?;
-                    cmd.undo(model);
-                    *cur_cmd_seq_no -= 1;
-                    Self::save_seq_no(sqlite_path, conn, *cur_cmd_seq_no)?;
-                    Ok((*cur_cmd_seq_no + 1, ser_cmd))
-                } else {
-                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
-                }
+    // Redo at the base moves to the oldest command.
+    fn try_redo(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        if self.at_base() {
+            self.move_to(0)?;
+        } else if self.can_redo() {
+            let mut location = self.location + 1;
+            while self.store.get(location).is_some_and(|entry| entry.grouped) {
+                location += 1;
             }
+            self.move_to(location)?;
         }
+        Ok(())
     }
 
-    fn redo(&mut self) -> Result<(i64, Vec<u8>), Report<SqliteUndoStoreError>> {
-        match &mut self.state {
-            PersisterServerState::Idle => {
-                send!(self.sender, PersistResp::RedoErr(SqliteUndoStoreError::NotOpend.into_report()));
-                Err(SqliteUndoStoreError::NotOpend.into_report())
-            }
-            PersisterServerState::Loaded { base_dir: _, sqlite_path, cur_cmd_seq_no, model, conn } => {
-                let db = Db::new(sqlite_path.clone(), conn);
-                let ser_cmd: Option<Vec<u8>> = db.exec(|conn| {
-                    let mut  stmt = conn.prepare(
-                        "select serialized from command where command_id = ?1"
-                    )?;
-                    let mut rows = stmt.query(rusqlite::params![*cur_cmd_seq_no + 1])?;
-                    let ser_cmd = match rows.next()? {
-                        Some(row) => {
-                            let ser: Vec<u8> = row.get(0)?;
-                            Some(ser)
-                        },
-                        None => None,
-                    };
-                    Ok(ser_cmd)
-                })?;
-                if let Some(ser_cmd) = ser_cmd {
-                    let cmd: C = bincode::deserialize(&ser_cmd).map_err(|ser_err|
-                        SqliteUndoStoreError::CannotDeserialize {
-                            path: Some(sqlite_path.clone()), seq_no: *cur_cmd_seq_no, ser_err
-                        }
-                    )?;
-                    cmd.redo(model);
-                    *cur_cmd_seq_no += 1;
-                    Self::save_seq_no(sqlite_path, conn, *cur_cmd_seq_no)?;
-                    Ok((*cur_cmd_seq_no - 1, ser_cmd))
-                } else {
-                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
-                }
-            }
+    fn seq_no(&self) -> i64 {
+        match self.base_seq_no() {
+            Some(seq_no) if self.at_base() => seq_no,
+            _ => self.removed_count + self.location as i64,
         }
     }
 
-    fn add_cmd(&mut self, seq_no: i64, ser_cmd: Vec<u8>) -> Result<(), Report<SqliteUndoStoreError>>{
-        match &mut self.state {
-            PersisterServerState::Idle => {
-                send!(self.sender, PersistResp::AddCmdErr(SqliteUndoStoreError::NotOpend.into_report()));
-                Err(SqliteUndoStoreError::NotOpend.into_report())
-            }
-            PersisterServerState::Loaded { base_dir: _, sqlite_path, cur_cmd_seq_no: _, model, conn } => {
-                let cmd: C = bincode::deserialize(&ser_cmd).map_err(|ser_err|
-                    SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err }
-                )?;
-                cmd.redo(model);
-
-                let seq_no = seq_no + 1;
-                let db = Db::new(sqlite_path.clone(), conn);
-                // This is the case you add commands after undo() some.
-                let delete_count = db.exec(|conn| conn.execute(
-                    "delete from command where ?1 <= command_id", rusqlite::params![seq_no]
-                ))?;
-                tracing::trace!("add_cmd() removed cmd (seqno <= {}): count: {}", seq_no, delete_count);
+    // Starts with the base snapshot if any. The states between it and the oldest command cannot be reached.
+    fn seq_no_range(&self) -> std::ops::RangeInclusive<i64> {
+        self.base_seq_no().unwrap_or(self.removed_count)..=self.removed_count + self.store.len() as i64
+    }
 
-                db.exec(|conn| conn.execute(
-                    "insert into command (command_id, serialized) values (?1, ?2)", rusqlite::params![seq_no, ser_cmd]
-                ))?;
-                tracing::trace!("add_cmd() inserted cmd seq no:{}", seq_no);
-
-                if seq_no == MAX_COMMAND_ID {
-                    tracing::error!("add_cmd() seq no reaced MAX_COMMAND_ID:{}", seq_no);
-                    send!(self.sender, PersistResp::AddCmdErr(SqliteUndoStoreError::NeedCompaction(sqlite_path.clone()).into_report()));
-                }
-                Self::save_seq_no(sqlite_path, conn, seq_no)?;
-                let removed_count = db.exec(|conn| Self::trim_undo_records(conn, self.undo_limit))?;
-                tracing::trace!("add_cmd() trimmed commands. Removed count: {}", removed_count);
-                if removed_count != 0 {
-                    let serialized = bincode::serialize(&model).map_err(SqliteUndoStoreError::from)?;
-
-                    match Self::get_last_snapshot_id(conn, &sqlite_path)? {
-                        None => {
-                            Self::save_snapshot(&db, &serialized, seq_no)?
-                        }
-                        Some(last_snapshot_id) => {
-                            if last_snapshot_id < seq_no - (self.undo_limit as i64) {
-                                Self::save_snapshot(&db, &serialized, seq_no)?
-                            }
-                        }
-                    }
-                }
-
-                if delete_count != 0 {
-                    db.exec(|conn| conn.execute("delete from snapshot", rusqlite::params![]))?;
-                    tracing::trace!("add_cmd() removed all snapshots.");
-                    let serialized = bincode::serialize(&model).map_err(SqliteUndoStoreError::from)?;
-                    Self::save_snapshot(&db, &serialized, seq_no)?;
-                } else {
-                    db.exec(|conn| Self::trim_snapshots(conn))?;
-                }
+    fn try_go_to(&mut self, seq_no: i64) -> Result<(), Report<InMemoryStoreErr>> {
+        if self.base_seq_no() == Some(seq_no) {
+            return self.move_to_base();
+        }
+        if !(self.removed_count..=self.removed_count + self.store.len() as i64).contains(&seq_no) {
+            return Err(Report::new(InMemoryStoreErr::SeqNoOutOfRange(seq_no)));
+        }
+        self.move_to((seq_no - self.removed_count) as usize)
+    }
 
-                Ok(())
-            }
+    fn begin_group(&mut self) {
+        if self.group.begin() {
+            // Do not merge commands across the group boundary.
+            self.last_added = None;
         }
     }
 
-    fn lock_file_path(base_dir: &std::path::Path) -> std::path::PathBuf {
-        let mut path: std::path::PathBuf = base_dir.to_path_buf();
-        path.push("lock");
-        path
+    fn end_group(&mut self) {
+        if self.group.end() {
+            self.last_added = None;
+        }
     }
 
-    fn try_lock(base_dir: &std::path::Path) -> Result<std::fs::File, Report<SqliteUndoStoreError>> {
-        let lock_file_path = Self::lock_file_path(base_dir);
-        std::fs::OpenOptions::new().write(true).create_new(true).open(&lock_file_path).map_err(|error|
-            SqliteUndoStoreError::CannotLock { path: lock_file_path, error }.into_report()
-        )
+    // A group is described by its first described command.
+    fn undo_description(&self) -> Option<String> {
+        let mut start = self.location.checked_sub(1)?;
+        while 0 < start && self.store[start].grouped {
+            start -= 1;
+        }
+        self.store.range(start..self.location).find_map(|entry| entry.cmd.describe())
     }
 
-    fn unlock(dir: &PathBuf) -> std::io::Result<()> {
-        let p = Self::lock_file_path(dir);
-        std::fs::remove_file(&p)
+    fn redo_description(&self) -> Option<String> {
+        if self.at_base() {
+            return None;
+        }
+        let first = self.store.get(self.location)?;
+        std::iter::once(first).chain(self.store.range(self.location + 1..).take_while(|entry| entry.grouped))
+            .find_map(|entry| entry.cmd.describe())
     }
 
-    #[inline]
-    fn open_existing<P: AsRef<std::path::Path>>(path: P) -> rusqlite::Result<rusqlite::Connection> {
-        rusqlite::Connection::open(&path)
+    fn model(&self) -> &M {
+        &self.model
     }
 
-    fn create_new<P: AsRef<std::path::Path>>(path: P) -> rusqlite::Result<rusqlite::Connection> {
-        let conn = rusqlite::Connection::open(&path)?;
-        Self::create_tables(&conn)?;
-        Ok(conn)
+    fn irreversible_mutate<R>(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> R>) -> R {
+        f(&mut self.model)
     }
 
-    fn create_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
-        conn.execute_batch(
-            "begin;
-            create table command(command_id integer primary key not null, serialized blob not null);
-            create table snapshot(snapshot_id integer primary key not null, serialized blob not null);
-            create table cmd_seq_no(cur_cmd_seq_no integer);
-            create table version(version integer not null);
-            insert into version (version) values (1);
-            commit;"
-        )?;
+    fn try_mark_clean(&mut self) -> Result<(), Report<InMemoryStoreErr>> {
+        self.clean_seq_no = Some(self.seq_no());
+        clear_branch_clean(&mut self.branches);
         Ok(())
     }
 
-    fn open_sqlite<P: AsRef<Path>>(dir: P, sqlite_path: PathBuf) -> Result<Connection, Report<SqliteUndoStoreError>> {
-        let conn = if sqlite_path.exists() {
-            if ! dir.as_ref().is_dir() {
-                return Err(SqliteUndoStoreError::NotADirectory(dir.as_ref().to_owned())).map_err(Report::from)
-            }
-            Self::try_lock(dir.as_ref())?;
-            Self::open_existing(&sqlite_path)
-        } else {
-            std::fs::create_dir_all(dir.as_ref()).map_err(|e| SqliteUndoStoreError::FileError(dir.as_ref().to_path_buf(), e))?;
-            Self::try_lock(dir.as_ref())?;
-            Self::create_new(&sqlite_path)
-        }.map_err(|e|
-            SqliteUndoStoreError::DbError(sqlite_path, e.into_report())
-        )?;
-        Ok(conn)        
+    fn is_dirty(&self) -> bool {
+        self.clean_seq_no != Some(self.seq_no())
     }
 
-    fn get_cur_seq_no(conn: &Connection) -> Result<i64, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "select count(cur_cmd_seq_no), max(cur_cmd_seq_no) from cmd_seq_no"
-        )?;
-        let mut rows = stmt.query([])?;
-                
-        let row = rows.next()?.unwrap();
-        let count: i64 = row.get(0)?;
-        if 1 < count {
-            panic!("Command sequence number inconsistent. The record count({}) should be 0 or 1.", count);
-        } else {
-            let cur_seq: Option<i64> = row.get(1)?;
-            match cur_seq {
-                None => {
-                    conn.execute("insert into cmd_seq_no (cur_cmd_seq_no) values (0)", rusqlite::params![])?;
-                    Ok(0)
-                },
-                Some(seq) => Ok(seq)
-            }
-        }
+    fn subscribe(&mut self, observer: Observer) -> SubscriptionId {
+        self.observers.subscribe(observer)
     }
 
-    fn restore_model(sqlite_path: &PathBuf, conn: &Connection) -> Result<(i64, M), Report<SqliteUndoStoreError>> {
-        let cur_seq_no = Self::get_cur_seq_no(conn).map_err(|e| SqliteUndoStoreError::DbError(sqlite_path.clone(), e.into_report()))?;
-        if cur_seq_no == 0 {
-            return Ok((0, M::default()))
-        }
-        
-        match Self::load_last_snapshot(&sqlite_path, conn)? {
-            Some((last_snapshot_id, mut model)) => {
-                tracing::trace!("loading snapshot. Snapshot id: {}, cmd seq no: {}.", last_snapshot_id, cur_seq_no);
-
-                // Restore with snapshot.
-                if cur_seq_no < last_snapshot_id {
-                    let mut stmt = Self::db(
-                        sqlite_path,
-                        || conn.prepare(
-                        "select command_id, serialized from command where ?1 < command_id and command_id <= ?2 order by command_id desc"
-                        )
-                    )?;
-                    let mut rows = Self::db(
-                        sqlite_path,
-                        || stmt.query([cur_seq_no, last_snapshot_id])
-                    )?;
-                            
-                    let mut cmd_id = last_snapshot_id;
+    fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.observers.unsubscribe(id)
+    }
+}
 
-                    while let Some(row) = Self::db(sqlite_path, || rows.next())? {
-                        let id: i64 = Self::db(sqlite_path, || row.get(0))?;
-                        tracing::trace!("loading snapshot. cmd id: {}.", id);
-                        if id != cmd_id {
-                            return Err(SqliteUndoStoreError::CannotRestoreModel {
-                                snapshot_id: Some(last_snapshot_id), not_foud_cmd_id: cmd_id
-                            }.into_report())
-                        }
-                        cmd_id -= 1;
-                                
-                        let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
-                        let cmd: C = bincode::deserialize(&serialized).map_err(|ser_err|
-                            SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
-                        )?;
-                        cmd.undo(&mut model);
-                    }
-                } else if last_snapshot_id < cur_seq_no {
-                    let mut stmt = Self::db(
-                        sqlite_path,
-                        || conn.prepare(
-                            "select command_id, serialized from command where ?1 < command_id and command_id <= ?2 order by command_id asc"
-                        )
-                    )?;
-                    let mut rows = Self::db(sqlite_path, || stmt.query([last_snapshot_id, cur_seq_no]))?;
-                            
-                    let mut cmd_id = last_snapshot_id + 1;
+// Nesting of begin_group()/end_group().
+#[derive(Debug, Default)]
+struct GroupState {
+    depth: usize,
+    // True if a command has been added in the current step.
+    has_cmd: bool,
+}
 
-                    while let Some(row) = Self::db(sqlite_path, || rows.next())? {
-                        let id: i64 = Self::db(
-                            sqlite_path, || row.get(0)
-                        )?;
-                        tracing::trace!("loading snapshot. cmd id: {}.", id);
-                        if id != cmd_id {
-                            return Err(SqliteUndoStoreError::CannotRestoreModel {
-                                snapshot_id: Some(last_snapshot_id), not_foud_cmd_id: cmd_id
-                            }.into_report())
-                        }
-                        cmd_id += 1;
-                        
-                        let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
-                        let cmd: C = bincode::deserialize(&serialized).map_err(|ser_err|
-                            SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
-                        )?;
-                        cmd.redo(&mut model);
-                    }
-                }
-        
-                Ok((cur_seq_no, model))
-            },
-            None => {
-                // Restore without snapshot.
-                Ok((cur_seq_no, Self::load_without_snapshot(sqlite_path, conn, cur_seq_no)?))
-            },
-        }
+impl GroupState {
+    // Returns true if the outermost group begins.
+    fn begin(&mut self) -> bool {
+        self.depth += 1;
+        if self.depth == 1 {
+            self.has_cmd = false;
+            true
+        } else { false }
     }
 
-    fn load_last_snapshot(sqlite_path: &PathBuf, conn: &Connection) -> Result<Option<(i64, M)>, Report<SqliteUndoStoreError>> {
-        let mut stmt = Self::db(
-            sqlite_path,
-            || conn.prepare(
-            "select snapshot_id, serialized from snapshot
-                    where
-                      snapshot_id >= (select min(command_id) from command) -1
-                      and snapshot_id <= (select max(command_id) from command)
-                    order by snapshot_id desc limit 1"
-            )
-        )?;
-        let mut rows = Self::db(sqlite_path, || stmt.query([]))?;
-        
-        if let Some(row) = Self::db(sqlite_path, || rows.next())? {
-            let id: i64 = Self::db(sqlite_path, || row.get(0))?;
-            let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
-            let snapshot: M = bincode::deserialize(&serialized).map_err(|ser_err|
-                SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
-            )?;
-            Ok(Some((id, snapshot)))
-        } else {
-            Ok(None)
+    // Returns true if the outermost group ends.
+    fn end(&mut self) -> bool {
+        match self.depth {
+            0 => false,
+            depth => {
+                self.depth = depth - 1;
+                self.depth == 0
+            }
         }
     }
 
-    fn load_without_snapshot(sqlite_path: &PathBuf, conn: &Connection, cur_seq_no: i64) -> Result<M, Report<SqliteUndoStoreError>> {
-        let mut stmt = Self::db(
-            sqlite_path,
-            || conn.prepare(
-                "select command_id, serialized from command where command_id <= ?1"
-            )
-        )?;
-        let mut rows = Self::db(sqlite_path, || stmt.query([cur_seq_no]))?;
+    // Returns true if the command to be added is undone/redone together with the previous one.
+    fn add_cmd(&mut self) -> bool {
+        let grouped = 0 < self.depth && self.has_cmd;
+        self.has_cmd = 0 < self.depth;
+        grouped
+    }
+}
 
-        let mut cmd_id = 1;
-        let mut model = M::default();
-        while let Some(row) = Self::db(sqlite_path, || rows.next())? {
-            let id: i64 = Self::db(sqlite_path, || row.get(0))?;
-            if id != cmd_id {
-                return Err(SqliteUndoStoreError::CannotRestoreModel { snapshot_id: None, not_foud_cmd_id: cmd_id }.into_report())
+// Undo the commands in reverse order if undo is true, otherwise redo them in order. If a command fails,
+// the applied ones are reverted and the index of the failed one is returned with the error.
+#[cfg(feature = "persistence")]
+fn apply_cmds<C: Cmd>(cmds: &[C], model: &mut C::Model, undo: bool) -> Result<(), (usize, CmdError)> {
+    if undo {
+        for (i, cmd) in cmds.iter().enumerate().rev() {
+            if let Err(error) = cmd.try_undo(model) {
+                // Best effort.
+                let _ = cmds[i + 1..].iter().try_for_each(|cmd| cmd.try_redo(model));
+                return Err((i, error));
+            }
+        }
+    } else {
+        for (i, cmd) in cmds.iter().enumerate() {
+            if let Err(error) = cmd.try_redo(model) {
+                let _ = cmds[..i].iter().rev().try_for_each(|cmd| cmd.try_undo(model));
+                return Err((i, error));
             }
-            cmd_id += 1;
-            
-            let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
-            let cmd: C = bincode::deserialize(&serialized).map_err(|ser_err|
-                SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
-            )?;
-            cmd.redo(&mut model);
         }
-        Ok(model)
     }
+    Ok(())
+}
 
-    fn save_seq_no(sqlite_path: &PathBuf, conn: &Connection, seq_no: i64) -> Result<(), Report<SqliteUndoStoreError>> {
-        Self::db(
-            &sqlite_path,
-            || conn.execute("update cmd_seq_no set cur_cmd_seq_no = ?1", rusqlite::params![seq_no])
-        )?;
-        tracing::trace!("Saved seq no: {}", seq_no);
-        Ok(())
+fn is_within_merge_timeout(merge_timeout: Option<Duration>, last_added: Option<Instant>, now: Instant) -> bool {
+    match (merge_timeout, last_added) {
+        (Some(timeout), Some(last_added)) => now.duration_since(last_added) <= timeout,
+        _ => false,
     }
+}
 
-    fn trim_undo_records(conn: &Connection, undo_limit: usize) -> std::result::Result<usize, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "delete from command as c0 where c0.command_id not in (
-                select command_id from command as c1 order by command_id desc limit ?1
-            )"
-        )?;
-        
-        Ok(stmt.execute(rusqlite::params![undo_limit as i64])?)
-    }
+// Sequence number, serialized command and whether it is grouped with the previous one.
+#[cfg(feature = "persistence")]
+type SerializedEntry = (i64, Vec<u8>, bool);
 
-    fn get_last_snapshot_id(conn: &Connection, sqlite_path: &PathBuf) -> Result<Option<i64>, Report<SqliteUndoStoreError>> {
-        let mut stmt = Self::db(
-            &sqlite_path,
-            || conn.prepare(
-                "select max(snapshot_id) from snapshot"
-            )
-        )?;
-        let mut rows = Self::db(&sqlite_path, || stmt.query([]))?;
-        let row = rows.next().unwrap();
-        Ok(row.unwrap().get(0).unwrap())
-    }
+// Recorded with each command. The time is in milliseconds since the Unix epoch.
+#[cfg(feature = "persistence")]
+#[derive(Debug)]
+struct CmdAttrs {
+    created_at: i64,
+    metadata: Option<Vec<u8>>,
+}
 
-    fn trim_snapshots(conn: &Connection) -> std::result::Result<usize, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "delete from snapshot where snapshot_id < (select max(snapshot_id) from snapshot)"
-        )?;
-        tracing::trace!("Snapshot trimmed.");
-        
-        Ok(stmt.execute(rusqlite::params![])?)
-    }
+#[cfg(feature = "persistence")]
+#[derive(Debug)]
+enum PersistCmd {
+    Open { dir: std::path::PathBuf, read_only: bool },
+    Close,
+    // The command is undone/redone together with the previous one if grouped is true.
+    AddCmd { seq_no: i64, ser_cmd: Vec<u8>, attrs: CmdAttrs, grouped: bool },
+    // Replace the command of seq_no with the merged one.
+    MergeCmd { seq_no: i64, ser_cmd: Vec<u8>, attrs: CmdAttrs },
+    Undo,
+    Redo,
+    Compact,
+    CompressionStats,
+    Branches,
+    // The branch should fork from the current position.
+    SwitchBranch { branch_id: i64 },
+    GoTo { seq_no: i64 },
+    History,
+    Descriptions,
+    CmdRecords,
+    MarkClean { seq_no: i64 },
+    // Start the empty store from the model at the sequence number.
+    Init { seq_no: i64, serialized_model: Vec<u8>, clean_seq_no: Option<i64> },
+}
 
-    fn save_snapshot(db: &Db, ser_model: &Vec<u8>, seq_no: i64) -> Result<(), Report<SqliteUndoStoreError>> {
-        db.exec(|conn| {
-            let mut stmt = conn.prepare(
-                "insert into snapshot (snapshot_id, serialized) values (?1, ?2)"
-            )?;
-            stmt.execute(rusqlite::params![seq_no, ser_model])
-        })?;
-        tracing::trace!("Snapshot saved: snapshot id: {}", seq_no);
-        
-        Ok(())
-    }
+#[cfg(feature = "persistence")]
+#[derive(Debug)]
+enum PersistResp {
+    OpenOk { serialized_model: Vec<u8>, seq_no: i64, min_max_seq_no: Option<(i64, i64)>, clean_seq_no: Option<i64>, codec: Arc<dyn Codec> },
+    OpenErr(Report<SqliteUndoStoreError>),
 
-    #[inline]
-    fn db<F, T>(sqlite_path: &PathBuf, f: F) -> Result<T, Report<SqliteUndoStoreError>> where F: FnOnce() -> std::result::Result<T, rusqlite::Error> {
-        f().map_err(|e| {
-            SqliteUndoStoreError::DbError(sqlite_path.clone(), e.into_report()).into_report()
-        })
-    }
-}
+    CloseOk,
+    #[allow(dead_code)]
+    CloseErr(Report<SqliteUndoStoreError>),
+
+    AddCmdOk { seq_no: i64 },
+    AddCmdErr { seq_no: i64, error: Report<SqliteUndoStoreError> },
+
+    MergeCmdOk { seq_no: i64 },
+    MergeCmdErr { seq_no: i64, error: Report<SqliteUndoStoreError> },
+
+    // grouped is true if the next command to undo/redo belongs to the same group.
+    UndoOk { seq_no: i64, serialized_command: Vec<u8>, grouped: bool },
+    UndoErr(Report<SqliteUndoStoreError>),
+
+    RedoOk { seq_no: i64, serialized_command: Vec<u8>, grouped: bool },
+    RedoErr(Report<SqliteUndoStoreError>),
+
+    CompactOk { offset: i64 },
+    CompactErr(Report<SqliteUndoStoreError>),
+
+    CompressionStatsOk(CompressionStats),
+    CompressionStatsErr(Report<SqliteUndoStoreError>),
+
+    BranchesOk(Vec<BranchInfo>),
+    BranchesErr(Report<SqliteUndoStoreError>),
+
+    SwitchBranchOk { max_seq_no: Option<i64>, clean_seq_no: Option<i64> },
+    SwitchBranchErr(Report<SqliteUndoStoreError>),
+
+    // Commands between the previous and the new sequence number in ascending order.
+    GoToOk { serialized_commands: Vec<Vec<u8>> },
+    GoToErr(Report<SqliteUndoStoreError>),
+
+    // In ascending order.
+    HistoryOk(Vec<SerializedEntry>),
+    HistoryErr(Report<SqliteUndoStoreError>),
+
+    DescriptionsOk { undo: Option<String>, redo: Option<String> },
+    DescriptionsErr(Report<SqliteUndoStoreError>),
+
+    CmdRecordsOk(Vec<CmdRecord>),
+    CmdRecordsErr(Report<SqliteUndoStoreError>),
+
+    MarkCleanOk,
+    MarkCleanErr(Report<SqliteUndoStoreError>),
+
+    InitOk,
+    InitErr(Report<SqliteUndoStoreError>),
+}
+
+#[cfg(feature = "persistence")]
+enum PersisterServerState<M>
+    where M: Default + serde::Serialize + serde::de::DeserializeOwned
+{
+    Idle,
+    Loaded {
+        sqlite_path: std::path::PathBuf,
+        cur_cmd_seq_no: i64,
+        model: M,
+        conn: rusqlite::Connection,
+        // None if opened in read-only mode.
+        lock_file: Option<std::fs::File>,
+    }
+}
+
+#[cfg(feature = "persistence")]
+struct PersisterServer<C, M, E>
+  where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
+{
+    phantom: std::marker::PhantomData<C>,
+    phantome: std::marker::PhantomData<E>,
+    receiver: Receiver<PersistCmd>,
+    sender: Sender<PersistResp>,
+    undo_limit: usize,
+    // Replaced with the codec recorded in the database on open.
+    codec: Arc<dyn Codec>,
+    compression_level: Option<u32>,
+    cipher: Option<Arc<dyn Cipher>>,
+    undo_tree: bool,
+    state: PersisterServerState<M>,
+}
+
+#[cfg(feature = "persistence")]
+struct PersisterClient {
+    receiver: Receiver<PersistResp>,
+    sender: Sender<PersistCmd>,
+    last_seq_no: i64,
+    last_processed_seq_no: Option<i64>,
+    min_seq_no: Option<i64>,
+    max_seq_no: Option<i64>,
+    // None if the clean point is no longer in the history.
+    clean_seq_no: Option<i64>,
+    undo_limit: usize,
+    errors: Vec<PersistError>,
+    on_persist_error: Option<Box<dyn FnMut(PersistError)>>,
+    // The first command that failed to persist. The database no longer follows the history after that.
+    failed_seq_no: Option<i64>,
+    // Kept here to notify the commands saved asynchronously.
+    observers: Observers,
+}
+
+/// An error occurred while the persister server asynchronously stores a command.
+#[cfg(feature = "persistence")]
+#[derive(Debug)]
+pub struct PersistError {
+    /// Sequence number of the command that was not persisted.
+    pub seq_no: i64,
+    pub error: Report<SqliteUndoStoreError>,
+}
+
+#[cfg(feature = "persistence")]
+impl PersisterClient {
+    // Returns the client, serialized model and the codec in use.
+    #[allow(clippy::type_complexity)]
+    fn open(
+        receiver: Receiver<PersistResp>, sender: Sender<PersistCmd>, dir: PathBuf, read_only: bool, undo_limit: usize,
+        on_persist_error: Option<Box<dyn FnMut(PersistError)>>, observers: Observers,
+    ) -> Result<(Self, Vec<u8>, Arc<dyn Codec>), Report<SqliteUndoStoreError>> 
+    {
+        sender.send(PersistCmd::Open { dir, read_only }).map_err(|_| SqliteUndoStoreError::CannotContactPersister)?;
+        let msg = receiver.recv().map_err(|_| SqliteUndoStoreError::CannotContactPersister)?;
+        let (serialized_model, seq_no, min_max_seq_no, clean_seq_no, codec) = match msg {
+            PersistResp::OpenOk { serialized_model, seq_no, min_max_seq_no, clean_seq_no, codec } =>
+                (serialized_model, seq_no, min_max_seq_no, clean_seq_no, codec),
+            PersistResp::OpenErr(report) => return Err(report),
+            resp => return Err(Self::unexpected_resp(resp)),
+        };
+        let (min_seq_no, max_seq_no) = if let Some((min, max)) = min_max_seq_no {
+            (Some(min), Some(max))
+        } else { (None, None) };
+
+        Ok((
+            Self {
+                receiver, sender, last_seq_no: seq_no, min_seq_no, max_seq_no, clean_seq_no, last_processed_seq_no: None, undo_limit,
+                errors: vec![], on_persist_error, failed_seq_no: None, observers,
+            },
+            serialized_model,
+            codec,
+        ))
+    }
+
+    fn report_error(&mut self, err: PersistError) {
+        // The command was stored even if the sequence number reaches the limit.
+        if self.failed_seq_no.is_none() && !matches!(err.error.current_context(), SqliteUndoStoreError::NeedCompaction(_)) {
+            self.failed_seq_no = Some(err.seq_no);
+        }
+        match &mut self.on_persist_error {
+            Some(f) => f(err),
+            None => self.errors.push(err),
+        }
+    }
+
+    fn unexpected_resp(resp: PersistResp) -> Report<SqliteUndoStoreError> {
+        tracing::error!("Unexpected response {:?}", resp);
+        SqliteUndoStoreError::CmdSequenceError.into_report()
+    }
+
+    fn undo(&mut self) -> Result<(i64, Vec<u8>, bool), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Undo)?;
+        let (seq_no, serialized_command, grouped) = match self.wait_resp()? {
+            PersistResp::UndoOk { seq_no, serialized_command, grouped } => (seq_no, serialized_command, grouped),
+            PersistResp::UndoErr(err) => return Err(err),
+            resp => return Err(Self::unexpected_resp(resp)),
+        };
+        if seq_no != self.last_seq_no {
+            tracing::error!("Unexpected sequence number: {} != {}", seq_no, self.last_seq_no);
+            error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+        }
+
+        self.last_seq_no -= 1;
+        Ok((seq_no, serialized_command, grouped))
+    }
+
+    fn redo(&mut self) -> Result<(i64, Vec<u8>, bool), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Redo)?;
+        let (seq_no, serialized_command, grouped) = match self.wait_resp()? {
+            PersistResp::RedoOk { seq_no, serialized_command, grouped } => (seq_no, serialized_command, grouped),
+            PersistResp::RedoErr(err) => return Err(err),
+            resp => return Err(Self::unexpected_resp(resp)),
+        };
+        if seq_no != self.last_seq_no {
+            tracing::error!("Unexpected sequence number: {} != {}", seq_no, self.last_seq_no);
+            error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+        }
+
+        self.last_seq_no += 1;
+        Ok((seq_no, serialized_command, grouped))
+    }
+
+    // Fails once a command failed to persist so that the database is not modified out of sequence.
+    fn ensure_persisted(&self) -> Result<(), Report<SqliteUndoStoreError>> {
+        if let Some(seq_no) = self.failed_seq_no {
+            error_stack::bail!(SqliteUndoStoreError::PersistFailed { seq_no });
+        }
+        Ok(())
+    }
+
+    fn post_cmd(&self, cmd: PersistCmd) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.ensure_persisted()?;
+        self.sender.send(cmd).map_err(|_| SqliteUndoStoreError::CannotContactPersister.into_report())
+    }
+
+    fn add_command(&mut self, ser_cmd: Vec<u8>, attrs: CmdAttrs, grouped: bool) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::AddCmd { seq_no: self.last_seq_no, ser_cmd, attrs, grouped })?;
+        if let Some(max_seq_no) = self.max_seq_no.filter(|max| self.last_seq_no < *max) {
+            if self.clean_seq_no.is_some_and(|clean_seq_no| self.last_seq_no < clean_seq_no) {
+                self.clean_seq_no = None;
+            }
+            self.observers.notify(StoreEvent::HistoryTruncated { seq_nos: self.last_seq_no + 1..=max_seq_no });
+        }
+        self.last_seq_no += 1;
+        // Redo history is gone. Do not wait for the response to reflect it.
+        self.max_seq_no = Some(self.last_seq_no);
+        match self.min_seq_no {
+            Some(min_seq_no) => {
+                if min_seq_no + (self.undo_limit as i64) <= self.last_seq_no {
+                    let new_min_seq_no = self.last_seq_no - (self.undo_limit as i64) + 1;
+                    self.min_seq_no = Some(new_min_seq_no);
+                    // The states before the removed commands cannot be reached anymore.
+                    if self.clean_seq_no.is_some_and(|clean_seq_no| clean_seq_no < new_min_seq_no - 1) {
+                        self.clean_seq_no = None;
+                    }
+                    self.observers.notify(StoreEvent::HistoryTruncated { seq_nos: min_seq_no..=new_min_seq_no - 1 });
+                }
+            }
+            None => self.min_seq_no = Some(self.last_seq_no),
+        }
+        self.observers.notify(StoreEvent::CmdAdded { seq_no: self.last_seq_no, merged: false });
+        Ok(())
+    }
+
+    fn merge_command(&mut self, ser_cmd: Vec<u8>, attrs: CmdAttrs) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::MergeCmd { seq_no: self.last_seq_no, ser_cmd, attrs })?;
+        if self.clean_seq_no == Some(self.last_seq_no) {
+            self.clean_seq_no = None;
+        }
+        Ok(())
+    }
+
+    fn mark_clean(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::MarkClean { seq_no: self.last_seq_no })?;
+        match self.wait_resp()? {
+            PersistResp::MarkCleanOk => {
+                self.clean_seq_no = Some(self.last_seq_no);
+                Ok(())
+            }
+            PersistResp::MarkCleanErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn init(&mut self, seq_no: i64, serialized_model: Vec<u8>, clean_seq_no: Option<i64>) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Init { seq_no, serialized_model, clean_seq_no })?;
+        match self.wait_resp()? {
+            PersistResp::InitOk => {
+                self.last_seq_no = seq_no;
+                self.clean_seq_no = clean_seq_no;
+                Ok(())
+            }
+            PersistResp::InitErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn compact(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Compact)?;
+        let offset = match self.wait_resp()? {
+            PersistResp::CompactOk { offset } => offset,
+            PersistResp::CompactErr(err) => return Err(err),
+            resp => return Err(Self::unexpected_resp(resp)),
+        };
+
+        self.last_seq_no -= offset;
+        self.last_processed_seq_no = self.last_processed_seq_no.map(|seq_no| seq_no - offset);
+        self.min_seq_no = self.min_seq_no.map(|seq_no| seq_no - offset);
+        self.max_seq_no = self.max_seq_no.map(|seq_no| seq_no - offset);
+        self.clean_seq_no = self.clean_seq_no.map(|seq_no| seq_no - offset);
+        Ok(())
+    }
+
+    fn compression_stats(&mut self) -> Result<CompressionStats, Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::CompressionStats)?;
+        match self.wait_resp()? {
+            PersistResp::CompressionStatsOk(stats) => Ok(stats),
+            PersistResp::CompressionStatsErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn branches(&mut self) -> Result<Vec<BranchInfo>, Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Branches)?;
+        match self.wait_resp()? {
+            PersistResp::BranchesOk(branches) => Ok(branches),
+            PersistResp::BranchesErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn switch_branch(&mut self, branch_id: i64) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::SwitchBranch { branch_id })?;
+        match self.wait_resp()? {
+            PersistResp::SwitchBranchOk { max_seq_no, clean_seq_no } => {
+                self.max_seq_no = max_seq_no;
+                self.clean_seq_no = clean_seq_no;
+                Ok(())
+            }
+            PersistResp::SwitchBranchErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn go_to(&mut self, seq_no: i64) -> Result<Vec<Vec<u8>>, Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::GoTo { seq_no })?;
+        match self.wait_resp()? {
+            PersistResp::GoToOk { serialized_commands } => {
+                self.last_seq_no = seq_no;
+                Ok(serialized_commands)
+            }
+            PersistResp::GoToErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn history(&mut self) -> Result<Vec<SerializedEntry>, Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::History)?;
+        match self.wait_resp()? {
+            PersistResp::HistoryOk(history) => Ok(history),
+            PersistResp::HistoryErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn cmd_records(&mut self) -> Result<Vec<CmdRecord>, Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::CmdRecords)?;
+        match self.wait_resp()? {
+            PersistResp::CmdRecordsOk(records) => Ok(records),
+            PersistResp::CmdRecordsErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    // Returns the descriptions of the commands to be undone and redone.
+    fn descriptions(&mut self) -> Result<(Option<String>, Option<String>), Report<SqliteUndoStoreError>> {
+        self.post_cmd(PersistCmd::Descriptions)?;
+        match self.wait_resp()? {
+            PersistResp::DescriptionsOk { undo, redo } => Ok((undo, redo)),
+            PersistResp::DescriptionsErr(err) => Err(err),
+            resp => Err(Self::unexpected_resp(resp)),
+        }
+    }
+
+    fn seq_no_range(&self) -> std::ops::RangeInclusive<i64> {
+        match (self.min_seq_no, self.max_seq_no) {
+            (Some(min), Some(max)) => min - 1..=max,
+            _ => self.last_seq_no..=self.last_seq_no,
+        }
+    }
+
+    // Handle responses that are sent asynchronously. Returns other responses as is.
+    fn process_async_resp(&mut self, resp: PersistResp) -> Result<Option<PersistResp>, Report<SqliteUndoStoreError>> {
+        match resp {
+            PersistResp::AddCmdOk { seq_no } | PersistResp::MergeCmdOk { seq_no } => {
+                // max_seq_no has been updated when the command was posted. The commands after this may already be posted.
+                self.last_processed_seq_no = Some(seq_no);
+                self.observers.notify(StoreEvent::Saved { seq_no });
+                Ok(None)
+            }
+            PersistResp::AddCmdErr { seq_no, error } | PersistResp::MergeCmdErr { seq_no, error } => {
+                self.last_processed_seq_no = Some(seq_no);
+                self.report_error(PersistError { seq_no, error });
+                Ok(None)
+            }
+            resp => Ok(Some(resp)),
+        }
+    }
+
+    fn process_resp(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(resp) => {
+                    if let Some(resp) = self.process_async_resp(resp)? {
+                        return Err(Self::unexpected_resp(resp));
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => return Ok(()),
+                Err(mpsc::TryRecvError::Disconnected) => error_stack::bail!(SqliteUndoStoreError::CannotContactPersister),
+            }
+        }
+    }
+
+    // Wait for a response, processing asynchronous responses received before it.
+    fn wait_resp(&mut self) -> Result<PersistResp, Report<SqliteUndoStoreError>> {
+        loop {
+            let resp = self.receiver.recv().map_err(|_| SqliteUndoStoreError::CannotContactPersister)?;
+            if let Some(resp) = self.process_async_resp(resp)? {
+                return Ok(resp);
+            }
+        }
+    }
+
+    fn can_undo(&self) -> bool {
+        if let Some(min_seq_no) = self.min_seq_no {
+            min_seq_no <= self.last_seq_no
+        } else { false }
+    }
+
+    fn can_redo(&self) -> bool {
+        if let Some(max_seq_no) = self.max_seq_no {
+            self.last_seq_no < max_seq_no
+        } else { false }
+    }
+
+    fn saved(&self) -> Result<bool, Report<SqliteUndoStoreError>> {
+        self.ensure_persisted()?;
+        if let Some(proceesed_seq_no) = self.last_processed_seq_no {
+            Ok(proceesed_seq_no == self.last_seq_no)
+        } else { Ok(false) }
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl Drop for PersisterClient {
+    fn drop(&mut self) {
+        if self.sender.send(PersistCmd::Close).is_err() {
+            return;
+        }
+        loop {
+            match self.receiver.recv() {
+                Ok(PersistResp::CloseOk) => break,
+                Ok(PersistResp::CloseErr(err)) => {
+                    tracing::error!("Unexpeced close error: {:?}", err);
+                    break;
+                }
+                Ok(PersistResp::AddCmdErr { seq_no, error } | PersistResp::MergeCmdErr { seq_no, error }) => {
+                    self.report_error(PersistError { seq_no, error });
+                }
+                Ok(PersistResp::UndoErr(err)) => {
+                    tracing::error!("Undo error: {:?}", err);
+                }
+                Ok(PersistResp::RedoErr(err)) => {
+                    tracing::error!("Redo error: {:?}", err);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!("Fail to communicate persister server: {:?}", err);
+                    break;
+                }
+            }
+        }
+        for err in self.errors.drain(..) {
+            tracing::error!("Persist error: {:?}", err);
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+macro_rules! send {
+    ($sender:expr, $msg:expr) => {
+        tracing::trace!("Persister server response: {:?}", $msg);
+        $sender.send($msg).unwrap();
+    };
+}
+
+
+#[cfg(feature = "persistence")]
+impl<C, M, E> PersisterServer<C, M, E>
+    where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
+{
+    fn new(
+        receiver: Receiver<PersistCmd>,
+        sender: Sender<PersistResp>,
+        undo_limit: usize,
+        codec: Arc<dyn Codec>,
+        compression_level: Option<u32>,
+        cipher: Option<Arc<dyn Cipher>>,
+        undo_tree: bool,
+    ) -> Self {
+        Self {
+            undo_limit, codec, compression_level, cipher, undo_tree,
+            phantom: std::marker::PhantomData, phantome: std::marker::PhantomData,
+            receiver, sender, state: PersisterServerState::Idle
+        }
+    }
+
+    fn start(mut self) {
+        loop {
+            let msg = self.receiver.recv();
+            tracing::trace!("PersisterServer received msg: {:?}", msg);
+            match msg {
+                Ok(cmd) => {
+                    match cmd {
+                        PersistCmd::Open { dir, read_only } => {
+                            match self.open(dir, read_only) {
+                                Ok((serialized_model, seq_no, min_max_seq_no, clean_seq_no, codec)) => {
+                                    let msg = PersistResp::OpenOk { serialized_model, seq_no, min_max_seq_no, clean_seq_no, codec };
+                                    send!(self.sender, msg);
+                                }
+                                Err(err) => {
+                                    tracing::error!("Cannot open: {:?}", err);
+                                    let msg = PersistResp::OpenErr(err);
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::Close => {
+                            if let PersisterServerState::Loaded { lock_file: Some(_), sqlite_path, .. } = &self.state {
+                                // Keep the lock file itself. Removing it could let two processes lock different files.
+                                if let Some(base_dir) = sqlite_path.parent() {
+                                    if let Err(e) = Self::clear_lock_owner(base_dir) {
+                                        tracing::error!("Cannot clear lock owner file: {:?}", e);
+                                    }
+                                }
+                            } else {
+                                tracing::trace!("Already closed.");
+                            }
+                            // Dropping the state releases the lock.
+                            self.state = PersisterServerState::Idle;
+                            send!(self.sender, PersistResp::CloseOk);
+                            break;
+                        }
+                        PersistCmd::AddCmd { seq_no, ser_cmd, attrs, grouped } => {
+                            match self.add_cmd(seq_no, ser_cmd, attrs, grouped) {
+                                Ok(_) => {
+                                    tracing::trace!("Cmd add ok");
+                                    let seq_no = seq_no + 1;
+                                    if let PersisterServerState::Loaded { cur_cmd_seq_no, .. } = &mut self.state {
+                                        *cur_cmd_seq_no = seq_no;
+                                    }
+                                    send!(self.sender, PersistResp::AddCmdOk { seq_no });
+                                },
+                                Err(error) => {
+                                    tracing::error!("Add cmd error {:?}", error);
+                                    let msg = PersistResp::AddCmdErr { seq_no: seq_no + 1, error };
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::MergeCmd { seq_no, ser_cmd, attrs } => {
+                            match self.merge_cmd(seq_no, ser_cmd, attrs) {
+                                Ok(_) => {
+                                    tracing::trace!("Cmd merge ok");
+                                    send!(self.sender, PersistResp::MergeCmdOk { seq_no });
+                                },
+                                Err(error) => {
+                                    tracing::error!("Merge cmd error {:?}", error);
+                                    let msg = PersistResp::MergeCmdErr { seq_no, error };
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::Undo => {
+                            match self.undo() {
+                                Ok((seq_no, serialized_command, grouped)) => {
+                                    tracing::trace!("Undo ok seq:{}", seq_no);
+                                    let msg = PersistResp::UndoOk { seq_no, serialized_command, grouped };
+                                    send!(self.sender, msg);
+                                }
+                                Err(err) => {
+                                    tracing::error!("Undo err {:?}", err);
+                                    let msg = PersistResp::UndoErr(err);
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::Redo => {
+                            match self.redo() {
+                                Ok((seq_no, serialized_command, grouped)) => {
+                                    tracing::trace!("Redo ok seq:{}", seq_no);
+                                    let msg = PersistResp::RedoOk { seq_no, serialized_command, grouped };
+                                    send!(self.sender, msg);
+                                }
+                                Err(err) => {
+                                    tracing::error!("Redo err {:?}", err);
+                                    let msg = PersistResp::RedoErr(err);
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::Compact => {
+                            match self.compact() {
+                                Ok(offset) => {
+                                    tracing::trace!("Compact ok offset:{}", offset);
+                                    send!(self.sender, PersistResp::CompactOk { offset });
+                                }
+                                Err(err) => {
+                                    tracing::error!("Compact err {:?}", err);
+                                    let msg = PersistResp::CompactErr(err);
+                                    send!(self.sender, msg);
+                                }
+                            }
+                        }
+                        PersistCmd::CompressionStats => {
+                            let msg = match self.compression_stats() {
+                                Ok(stats) => PersistResp::CompressionStatsOk(stats),
+                                Err(err) => PersistResp::CompressionStatsErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::Branches => {
+                            let msg = match self.branches() {
+                                Ok(branches) => PersistResp::BranchesOk(branches),
+                                Err(err) => PersistResp::BranchesErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::CmdRecords => {
+                            let msg = match self.cmd_records() {
+                                Ok(records) => PersistResp::CmdRecordsOk(records),
+                                Err(err) => PersistResp::CmdRecordsErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::Descriptions => {
+                            let msg = match self.descriptions() {
+                                Ok((undo, redo)) => PersistResp::DescriptionsOk { undo, redo },
+                                Err(err) => PersistResp::DescriptionsErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::MarkClean { seq_no } => {
+                            let msg = match self.mark_clean(seq_no) {
+                                Ok(()) => PersistResp::MarkCleanOk,
+                                Err(err) => PersistResp::MarkCleanErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::Init { seq_no, serialized_model, clean_seq_no } => {
+                            let msg = match self.init(seq_no, serialized_model, clean_seq_no) {
+                                Ok(()) => PersistResp::InitOk,
+                                Err(err) => {
+                                    tracing::error!("Init err {:?}", err);
+                                    PersistResp::InitErr(err)
+                                }
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::History => {
+                            let msg = match self.history() {
+                                Ok(history) => PersistResp::HistoryOk(history),
+                                Err(err) => PersistResp::HistoryErr(err),
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::GoTo { seq_no } => {
+                            let msg = match self.go_to(seq_no) {
+                                Ok(serialized_commands) => PersistResp::GoToOk { serialized_commands },
+                                Err(err) => {
+                                    tracing::error!("Go to err {:?}", err);
+                                    PersistResp::GoToErr(err)
+                                }
+                            };
+                            send!(self.sender, msg);
+                        }
+                        PersistCmd::SwitchBranch { branch_id } => {
+                            let msg = match self.switch_branch(branch_id) {
+                                Ok((max_seq_no, clean_seq_no)) => PersistResp::SwitchBranchOk { max_seq_no, clean_seq_no },
+                                Err(err) => {
+                                    tracing::error!("Switch branch err {:?}", err);
+                                    PersistResp::SwitchBranchErr(err)
+                                }
+                            };
+                            send!(self.sender, msg);
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("Persister server cannot contact the client {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Returns serialized model, current sequence number, min/max sequence numbers of commands, the clean point and the codec in use.
+    #[allow(clippy::type_complexity)]
+    fn open(&mut self, dir: PathBuf, read_only: bool) -> Result<(Vec<u8>, i64, Option<(i64, i64)>, Option<i64>, Arc<dyn Codec>), Report<SqliteUndoStoreError>> {
+        if let PersisterServerState::Loaded { .. } = &self.state {
+            error_stack::bail!(SqliteUndoStoreError::AlreadyOpened);
+        }
+
+        let sqlite_path = dir.join(SQLITE_FILE_NAME);
+        let (conn, lock_file) = if read_only {
+            (Self::open_sqlite_read_only(sqlite_path.clone())?, None)
+        } else {
+            let (conn, lock_file) = Self::open_sqlite(dir, sqlite_path.clone())?;
+            (conn, Some(lock_file))
+        };
+        tracing::trace!("Succeed to open sqlite file: {:?}", sqlite_path);
+        let codec = Self::resolve_codec(&sqlite_path, &conn, self.codec.clone())?;
+        Self::verify_key(&sqlite_path, &conn, self.cipher.as_deref())?;
+        let (cur_cmd_seq_no, model) = Self::restore_model(&sqlite_path, &conn, codec.as_ref(), self.cipher.as_deref())?;
+        tracing::trace!("Succeed to restore model(seq: {})", cur_cmd_seq_no);
+        let serialized_model = codec::serialize(codec.as_ref(), &model).map_err(|ser_err|
+            SqliteUndoStoreError::CannotDeserialize { path: Some(sqlite_path.clone()), seq_no: cur_cmd_seq_no, ser_err }
+        )?;
+        let db = Db::new(sqlite_path.clone(), &conn);
+        let min_max_seq_no = Self::min_max_seq_no(&db)?;
+        tracing::trace!("Min/Max: {:?}", min_max_seq_no);
+        let clean_seq_no: Option<i64> = db.exec(|conn|
+            conn.query_row("select seq_no from clean_point", [], |row| row.get(0)).optional()
+        )?.flatten();
+
+        self.state = PersisterServerState::Loaded {
+            sqlite_path, cur_cmd_seq_no, model, conn, lock_file,
+        };
+        self.codec = codec.clone();
+        Ok((serialized_model, cur_cmd_seq_no, min_max_seq_no, clean_seq_no, codec))
+    }
+
+    // Returns the codec recorded in the database. The specified one is recorded if the database has nothing stored yet.
+    fn resolve_codec(sqlite_path: &Path, conn: &Connection, specified: Arc<dyn Codec>) -> Result<Arc<dyn Codec>, Report<SqliteUndoStoreError>> {
+        let recorded: Option<String> = Self::db(sqlite_path, || {
+            let mut stmt = conn.prepare("select value from metadata where key = 'codec'")?;
+            let mut rows = stmt.query([])?;
+            match rows.next()? {
+                Some(row) => row.get(0),
+                None => Ok(None),
+            }
+        })?;
+        let name = match recorded {
+            Some(name) => name,
+            None => {
+                // Databases created before codecs were introduced are stored by bincode.
+                let name = if Self::is_empty(sqlite_path, conn)? { specified.name().to_owned() } else { codec::BINCODE_CODEC_NAME.to_owned() };
+                if !Self::db(sqlite_path, || conn.is_readonly(rusqlite::MAIN_DB))? {
+                    Self::db(sqlite_path, || conn.execute("insert into metadata (key, value) values ('codec', ?1)", [&name]))?;
+                }
+                name
+            }
+        };
+
+        if name == specified.name() {
+            Ok(specified)
+        } else {
+            codec::builtin_codec(&name).ok_or_else(||
+                SqliteUndoStoreError::UnknownCodec { path: sqlite_path.to_path_buf(), name }.into_report()
+            )
+        }
+    }
+
+    // Check that the cipher is the same as the one used to store the database.
+    fn verify_key(sqlite_path: &Path, conn: &Connection, cipher: Option<&dyn Cipher>) -> Result<(), Report<SqliteUndoStoreError>> {
+        let key_check: Option<Vec<u8>> = Self::db(sqlite_path, || conn.query_row(
+            "select value from metadata where key = 'key_check'", [], |row| row.get(0)
+        ).optional())?;
+        match (key_check, cipher) {
+            (None, None) => Ok(()),
+            (Some(_), None) => Err(SqliteUndoStoreError::KeyRequired(sqlite_path.to_path_buf()).into_report()),
+            (Some(key_check), Some(cipher)) => match cipher.decrypt(&key_check) {
+                Ok(decrypted) if decrypted == cipher::KEY_CHECK => Ok(()),
+                _ => Err(SqliteUndoStoreError::WrongKey(sqlite_path.to_path_buf()).into_report()),
+            },
+            (None, Some(cipher)) => {
+                if !Self::is_empty(sqlite_path, conn)? {
+                    error_stack::bail!(SqliteUndoStoreError::NotEncrypted(sqlite_path.to_path_buf()));
+                }
+                if !Self::db(sqlite_path, || conn.is_readonly(rusqlite::MAIN_DB))? {
+                    let key_check = cipher.encrypt(cipher::KEY_CHECK)
+                        .map_err(|e| SqliteUndoStoreError::CipherError(sqlite_path.to_path_buf(), e))?;
+                    Self::db(sqlite_path, || conn.execute("insert into metadata (key, value) values ('key_check', ?1)", [key_check]))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // True if neither commands nor snapshots are stored.
+    fn is_empty(sqlite_path: &Path, conn: &Connection) -> Result<bool, Report<SqliteUndoStoreError>> {
+        Self::db(sqlite_path, || conn.query_row(
+            "select not exists (select * from command) and not exists (select * from snapshot)", [], |row| row.get(0)
+        ))
+    }
+
+    fn min_max_seq_no(db: &Db) -> Result<Option<(i64, i64)>, Report<SqliteUndoStoreError>> {
+        db.exec(|conn| {
+            let mut stmt = conn.prepare(
+                "select count(command_id), min(command_id), max(command_id) from command"
+            )?;
+            let mut rows = stmt.query([])?;
+            let row = rows.next()?.unwrap();
+            let count: i64 = row.get(0)?;
+            Ok(
+                if count == 0 {
+                    None
+                } else {
+                    let min: i64 = row.get(1)?;
+                    let max: i64 = row.get(2)?;
+                    Some((min, max))
+                }
+            )
+        })
+    }
+
+    // Returns true as well if the undone command is grouped with the previous one.
+    fn undo(&mut self) -> Result<(i64, Vec<u8>, bool), Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                send!(self.sender, PersistResp::UndoErr(SqliteUndoStoreError::NotOpend.into_report()));
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                let db = Db::new(sqlite_path.clone(), conn);
+                let ser_cmd = Self::load_cmd(&db, *cur_cmd_seq_no, self.cipher.as_deref())?;
+                if let Some(ser_cmd) = ser_cmd {
+                    let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+                        SqliteUndoStoreError::CannotDeserialize {
+                            path: Some(sqlite_path.clone()), seq_no: *cur_cmd_seq_no, ser_err
+                        }
+                    )?;
+                    cmd.try_undo(model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: *cur_cmd_seq_no, error })?;
+                    let grouped = Self::is_grouped(&db, *cur_cmd_seq_no)?;
+                    *cur_cmd_seq_no -= 1;
+                    Self::save_seq_no(sqlite_path, conn, *cur_cmd_seq_no)?;
+                    Ok((*cur_cmd_seq_no + 1, ser_cmd, grouped))
+                } else {
+                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
+                }
+            }
+        }
+    }
+
+    // Returns true as well if the next command is grouped with the redone one.
+    fn redo(&mut self) -> Result<(i64, Vec<u8>, bool), Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                send!(self.sender, PersistResp::RedoErr(SqliteUndoStoreError::NotOpend.into_report()));
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                let db = Db::new(sqlite_path.clone(), conn);
+                let ser_cmd = Self::load_cmd(&db, *cur_cmd_seq_no + 1, self.cipher.as_deref())?;
+                if let Some(ser_cmd) = ser_cmd {
+                    let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+                        SqliteUndoStoreError::CannotDeserialize {
+                            path: Some(sqlite_path.clone()), seq_no: *cur_cmd_seq_no, ser_err
+                        }
+                    )?;
+                    cmd.try_redo(model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: *cur_cmd_seq_no + 1, error })?;
+                    *cur_cmd_seq_no += 1;
+                    let grouped = Self::is_grouped(&db, *cur_cmd_seq_no + 1)?;
+                    Self::save_seq_no(sqlite_path, conn, *cur_cmd_seq_no)?;
+                    Ok((*cur_cmd_seq_no - 1, ser_cmd, grouped))
+                } else {
+                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
+                }
+            }
+        }
+    }
+
+    fn add_cmd(&mut self, seq_no: i64, ser_cmd: Vec<u8>, attrs: CmdAttrs, grouped: bool) -> Result<(), Report<SqliteUndoStoreError>>{
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                // A previous command failed. Adding this one would leave a gap.
+                if seq_no != *cur_cmd_seq_no {
+                    error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+                }
+                let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+                    SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err }
+                )?;
+                cmd.try_redo(model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: seq_no + 1, error })?;
+
+                let seq_no = seq_no + 1;
+                let db = Db::new(sqlite_path.clone(), conn);
+                // This is the case you add commands after undo() some.
+                let delete_count = if self.undo_tree {
+                    db.exec(|conn| {
+                        let tx = conn.unchecked_transaction()?;
+                        let count = Self::stash_redo_history(&tx, seq_no - 1)?;
+                        tx.commit()?;
+                        Ok(count)
+                    })?
+                } else {
+                    db.exec(|conn| {
+                        Self::delete_branches(conn, i64::MIN, seq_no - 1)?;
+                        conn.execute("delete from command where ?1 <= command_id", rusqlite::params![seq_no])
+                    })?
+                };
+                tracing::trace!("add_cmd() removed cmd (seqno <= {}): count: {}", seq_no, delete_count);
+                if delete_count != 0 {
+                    db.exec(|conn| conn.execute("update clean_point set seq_no = null where ?1 < seq_no", [seq_no - 1]))?;
+                }
+
+                let packed = Self::pack(sqlite_path, ser_cmd, self.compression_level, self.cipher.as_deref())?;
+                let metadata = Self::pack_metadata(sqlite_path, attrs.metadata, self.cipher.as_deref())?;
+                let description = Self::pack_description(sqlite_path, cmd.describe(), self.cipher.as_deref())?;
+                db.exec(|conn| conn.execute(
+                    "insert into command (command_id, serialized, compression, raw_size, description, created_at, metadata, grouped)
+                        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    rusqlite::params![seq_no, packed.data, packed.compression, packed.raw_size, description, attrs.created_at, metadata, grouped]
+                ))?;
+                tracing::trace!("add_cmd() inserted cmd seq no:{}", seq_no);
+
+                if seq_no == MAX_COMMAND_ID {
+                    tracing::error!("add_cmd() seq no reaced MAX_COMMAND_ID:{}", seq_no);
+                    send!(self.sender, PersistResp::AddCmdErr { seq_no, error: SqliteUndoStoreError::NeedCompaction(sqlite_path.clone()).into_report() });
+                }
+                Self::save_seq_no(sqlite_path, conn, seq_no)?;
+                let removed_count = db.exec(|conn| Self::trim_undo_records(conn, self.undo_limit))?;
+                tracing::trace!("add_cmd() trimmed commands. Removed count: {}", removed_count);
+                if removed_count != 0 {
+                    db.exec(|conn| {
+                        let min_cmd_id: i64 = conn.query_row("select min(command_id) from command", [], |row| row.get(0))?;
+                        // The states before the removed commands cannot be reached anymore.
+                        conn.execute("update clean_point set seq_no = null where seq_no < ?1", [min_cmd_id - 1])?;
+                        Self::delete_branches(conn, min_cmd_id - 1, i64::MAX)
+                    })?;
+                    let serialized = codec::serialize(self.codec.as_ref(), &model).map_err(SqliteUndoStoreError::SerializeError)?;
+
+                    match Self::get_last_snapshot_id(conn, sqlite_path)? {
+                        None => {
+                            Self::save_snapshot(&db, serialized, seq_no, self.compression_level, self.cipher.as_deref())?
+                        }
+                        Some(last_snapshot_id) => {
+                            if last_snapshot_id < seq_no - (self.undo_limit as i64) {
+                                Self::save_snapshot(&db, serialized, seq_no, self.compression_level, self.cipher.as_deref())?
+                            }
+                        }
+                    }
+                }
+
+                if delete_count != 0 {
+                    db.exec(|conn| conn.execute("delete from snapshot", rusqlite::params![]))?;
+                    tracing::trace!("add_cmd() removed all snapshots.");
+                    let serialized = codec::serialize(self.codec.as_ref(), &model).map_err(SqliteUndoStoreError::SerializeError)?;
+                    Self::save_snapshot(&db, serialized, seq_no, self.compression_level, self.cipher.as_deref())?;
+                } else {
+                    db.exec(|conn| Self::trim_snapshots(conn))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn merge_cmd(&mut self, seq_no: i64, ser_cmd: Vec<u8>, attrs: CmdAttrs) -> Result<(), Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                if seq_no != *cur_cmd_seq_no {
+                    error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+                }
+                let db = Db::new(sqlite_path.clone(), conn);
+                let last_ser_cmd = Self::load_cmd(&db, seq_no, self.cipher.as_deref())?;
+                let Some(last_ser_cmd) = last_ser_cmd else {
+                    error_stack::bail!(SqliteUndoStoreError::CmdSequenceError);
+                };
+                let last_cmd: C = codec::deserialize(self.codec.as_ref(), &last_ser_cmd).map_err(|ser_err|
+                    SqliteUndoStoreError::CannotDeserialize { path: Some(sqlite_path.clone()), seq_no, ser_err }
+                )?;
+                let merged_cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+                    SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err }
+                )?;
+                last_cmd.try_undo(model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no, error })?;
+                if let Err(error) = merged_cmd.try_redo(model) {
+                    // Best effort.
+                    let _ = last_cmd.try_redo(model);
+                    error_stack::bail!(SqliteUndoStoreError::CmdFailed { seq_no, error });
+                }
+
+                let packed = Self::pack(sqlite_path, ser_cmd, self.compression_level, self.cipher.as_deref())?;
+                let metadata = Self::pack_metadata(sqlite_path, attrs.metadata, self.cipher.as_deref())?;
+                let description = Self::pack_description(sqlite_path, merged_cmd.describe(), self.cipher.as_deref())?;
+                db.exec(|conn| conn.execute(
+                    "update command set serialized = ?2, compression = ?3, raw_size = ?4, description = ?5, created_at = ?6, metadata = ?7
+                        where command_id = ?1",
+                    rusqlite::params![seq_no, packed.data, packed.compression, packed.raw_size, description, attrs.created_at, metadata]
+                ))?;
+                tracing::trace!("merge_cmd() replaced cmd seq no:{}", seq_no);
+                db.exec(|conn| conn.execute("update clean_point set seq_no = null where seq_no = ?1", [seq_no]))?;
+
+                // The snapshot taken just after the replaced command is no longer valid.
+                let serialized = codec::serialize(self.codec.as_ref(), &model).map_err(SqliteUndoStoreError::SerializeError)?;
+                let packed = Self::pack(sqlite_path, serialized, self.compression_level, self.cipher.as_deref())?;
+                db.exec(|conn| conn.execute(
+                    "update snapshot set serialized = ?2, compression = ?3, raw_size = ?4 where snapshot_id = ?1",
+                    rusqlite::params![seq_no, packed.data, packed.compression, packed.raw_size]
+                ))?;
+
+                Ok(())
+            }
+        }
+    }
+
+    // Renumber commands and snapshots so that the command id starts with 1. Returns the amount shifted.
+    fn compact(&mut self) -> Result<i64, Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, conn, .. } => {
+                let offset = Self::db(sqlite_path, || {
+                    let tx = conn.transaction()?;
+                    let min_cmd_id: Option<i64> = tx.query_row("select min(command_id) from command", [], |row| row.get(0))?;
+                    let offset = min_cmd_id.map_or(0, |min| min - 1);
+                    if offset != 0 {
+                        tx.execute("delete from snapshot where snapshot_id < ?1", [offset])?;
+                        // Negate ids first so that renumbered ids never conflict with existing ones.
+                        tx.execute("update command set command_id = -command_id", [])?;
+                        tx.execute("update command set command_id = -command_id - ?1", [offset])?;
+                        tx.execute("update snapshot set snapshot_id = -snapshot_id", [])?;
+                        tx.execute("update snapshot set snapshot_id = -snapshot_id - ?1", [offset])?;
+                        tx.execute("update cmd_seq_no set cur_cmd_seq_no = cur_cmd_seq_no - ?1", [offset])?;
+                        tx.execute("update clean_point set seq_no = seq_no - ?1", [offset])?;
+                        tx.execute("update branch set fork = fork - ?1 where parent_id is null", [offset])?;
+                    }
+                    tx.commit()?;
+                    Ok(offset)
+                })?;
+                *cur_cmd_seq_no -= offset;
+                tracing::trace!("compact() shifted ids by {}", offset);
+                Ok(offset)
+            }
+        }
+    }
+
+    fn mark_clean(&mut self, seq_no: i64) -> Result<(), Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => Err(SqliteUndoStoreError::NotOpend.into_report()),
+            PersisterServerState::Loaded { sqlite_path, conn, .. } => {
+                Self::db(sqlite_path, || {
+                    conn.execute("update clean_point set seq_no = ?1", [seq_no])?;
+                    conn.execute("update branch_command set clean = 0 where clean", [])
+                })?;
+                Ok(())
+            }
+        }
+    }
+
+    // Save the model as the snapshot at the sequence number so that the commands added next start from it.
+    // Fails if commands or snapshots are already stored.
+    fn init(&mut self, seq_no: i64, serialized_model: Vec<u8>, clean_seq_no: Option<i64>) -> Result<(), Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => Err(SqliteUndoStoreError::NotOpend.into_report()),
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                if !Self::is_empty(sqlite_path, conn)? {
+                    error_stack::bail!(SqliteUndoStoreError::StoreExists(sqlite_path.clone()));
+                }
+                let new_model: M = codec::deserialize(self.codec.as_ref(), &serialized_model).map_err(|ser_err|
+                    SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err }
+                )?;
+                let db = Db::new(sqlite_path.clone(), conn);
+                Self::save_snapshot(&db, serialized_model, seq_no, self.compression_level, self.cipher.as_deref())?;
+                db.exec(|conn| conn.execute("update clean_point set seq_no = ?1", [clean_seq_no]))?;
+                Self::save_seq_no(sqlite_path, conn, seq_no)?;
+                *model = new_model;
+                *cur_cmd_seq_no = seq_no;
+                Ok(())
+            }
+        }
+    }
+
+    fn cmd_records(&mut self) -> Result<Vec<CmdRecord>, Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, conn, .. } => {
+                let rows: Vec<(i64, Option<i64>, Option<Vec<u8>>)> = Self::db(sqlite_path, || {
+                    let mut stmt = conn.prepare("select command_id, created_at, metadata from command order by command_id asc")?;
+                    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+                    rows.collect()
+                })?;
+                rows.into_iter().map(|(seq_no, created_at, metadata)| Ok(CmdRecord {
+                    seq_no,
+                    created_at: created_at.map(|millis| std::time::UNIX_EPOCH + Duration::from_millis(millis as u64)),
+                    metadata: metadata.map(|metadata| Self::unpack(sqlite_path, metadata, None, self.cipher.as_deref())).transpose()?,
+                })).collect()
+            }
+        }
+    }
+
+    // Read from the description column so that commands need not be deserialized.
+    fn descriptions(&mut self) -> Result<(Option<String>, Option<String>), Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, conn, .. } => {
+                // A group is described by its first described command.
+                let description = |sql: &str| Self::db(sqlite_path, || conn.query_row(
+                    sql, [*cur_cmd_seq_no], |row| row.get::<_, Value>(0)
+                ).optional()).and_then(|description|
+                    Self::unpack_description(sqlite_path, description.unwrap_or(Value::Null), self.cipher.as_deref())
+                );
+                let undo = description(
+                    "select description from command
+                        where coalesce((select max(command_id) from command where command_id <= ?1 and not grouped), 0) <= command_id
+                            and command_id <= ?1 and description is not null
+                        order by command_id limit 1"
+                )?;
+                let redo = description(
+                    "select description from command
+                        where ?1 < command_id
+                            and ifnull(command_id < (select min(command_id) from command where ?1 + 1 < command_id and not grouped), 1)
+                            and description is not null
+                        order by command_id limit 1"
+                )?;
+                Ok((undo, redo))
+            }
+        }
+    }
+
+    fn history(&mut self) -> Result<Vec<SerializedEntry>, Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, conn, .. } => {
+                let rows: Vec<(i64, Vec<u8>, Option<String>, bool)> = Self::db(sqlite_path, || {
+                    let mut stmt = conn.prepare("select command_id, serialized, compression, grouped from command order by command_id asc")?;
+                    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+                    rows.collect()
+                })?;
+                rows.into_iter().map(|(seq_no, serialized, compression, grouped)|
+                    Ok((seq_no, Self::unpack(sqlite_path, serialized, compression.as_deref(), self.cipher.as_deref())?, grouped))
+                ).collect()
+            }
+        }
+    }
+
+    // Move to the sequence number. Returns the commands in between in ascending order.
+    // The client replays them on its model, so they are replayed here as well instead of restoring a snapshot.
+    fn go_to(&mut self, seq_no: i64) -> Result<Vec<Vec<u8>>, Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                let db = Db::new(sqlite_path.clone(), conn);
+                let (min, max) = Self::min_max_seq_no(&db)?.unwrap_or((*cur_cmd_seq_no + 1, *cur_cmd_seq_no));
+                if seq_no < min - 1 || max < seq_no {
+                    error_stack::bail!(SqliteUndoStoreError::SeqNoOutOfRange(seq_no));
+                }
+                let (from, to) = if seq_no < *cur_cmd_seq_no { (seq_no, *cur_cmd_seq_no) } else { (*cur_cmd_seq_no, seq_no) };
+                let rows: Vec<(Vec<u8>, Option<String>)> = db.exec(|conn| {
+                    let mut stmt = conn.prepare(
+                        "select serialized, compression from command where ?1 < command_id and command_id <= ?2 order by command_id asc"
+                    )?;
+                    let rows = stmt.query_map([from, to], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                    rows.collect()
+                })?;
+                if rows.len() as i64 != to - from {
+                    error_stack::bail!(SqliteUndoStoreError::CannotRestoreModel { snapshot_id: None, not_foud_cmd_id: from + 1 });
+                }
+                let serialized_commands = rows.into_iter().map(|(serialized, compression)|
+                    Self::unpack(sqlite_path, serialized, compression.as_deref(), self.cipher.as_deref())
+                ).collect::<Result<Vec<_>, _>>()?;
+
+                let ids = from + 1..=to;
+                let cmds = ids.zip(serialized_commands.iter()).map(|(id, ser_cmd)|
+                    codec::deserialize::<C>(self.codec.as_ref(), ser_cmd).map_err(|ser_err|
+                        SqliteUndoStoreError::CannotDeserialize { path: Some(sqlite_path.clone()), seq_no: id, ser_err }
+                    )
+                ).collect::<Result<Vec<_>, _>>()?;
+                apply_cmds(&cmds, model, seq_no < *cur_cmd_seq_no).map_err(|(i, error)|
+                    SqliteUndoStoreError::CmdFailed { seq_no: from + 1 + i as i64, error }
+                )?;
+                *cur_cmd_seq_no = seq_no;
+                Self::save_seq_no(sqlite_path, conn, seq_no)?;
+                Ok(serialized_commands)
+            }
+        }
+    }
+
+    // Set the commands after the fork aside as a new branch. Branches forking from them become its children.
+    // Returns the number of commands moved.
+    fn stash_redo_history(conn: &Connection, fork: i64) -> rusqlite::Result<usize> {
+        let count: i64 = conn.query_row("select count(*) from command where ?1 < command_id", [fork], |row| row.get(0))?;
+        if count == 0 {
+            return Ok(0);
+        }
+        conn.execute("insert into branch (parent_id, fork) values (null, ?1)", [fork])?;
+        let branch_id = conn.last_insert_rowid();
+        conn.execute("update branch set parent_id = ?1, fork = fork - ?2 where parent_id is null and ?2 < fork", [branch_id, fork])?;
+        conn.execute(
+            "insert into branch_command (branch_id, idx, serialized, compression, raw_size, description, created_at, metadata, grouped)
+                select ?1, command_id - ?2 - 1, serialized, compression, raw_size, description, created_at, metadata, grouped
+                    from command where ?2 < command_id",
+            [branch_id, fork]
+        )?;
+        // The clean point moves with the branch.
+        conn.execute(
+            "update branch_command set clean = 1 where branch_id = ?1 and idx = (select seq_no from clean_point) - ?2 - 1",
+            [branch_id, fork]
+        )?;
+        conn.execute("update clean_point set seq_no = null where ?1 < seq_no", [fork])?;
+        conn.execute("delete from command where ?1 < command_id", [fork])?;
+        Ok(count as usize)
+    }
+
+    // Delete branches forking from the current history out of [min_fork, max_fork] with their descendants.
+    fn delete_branches(conn: &Connection, min_fork: i64, max_fork: i64) -> rusqlite::Result<()> {
+        for table in ["branch_command", "branch"] {
+            conn.execute(
+                &format!(
+                    "with recursive doomed(id) as (
+                        select branch_id from branch where parent_id is null and (fork < ?1 or ?2 < fork)
+                        union all select branch.branch_id from branch join doomed on branch.parent_id = doomed.id
+                    ) delete from {} where branch_id in (select id from doomed)",
+                    table
+                ),
+                [min_fork, max_fork]
+            )?;
+        }
+        Ok(())
+    }
+
+    fn branches(&mut self) -> Result<Vec<BranchInfo>, Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, conn, .. } => {
+                Self::db(sqlite_path, || {
+                    let mut stmt = conn.prepare(
+                        "select b.branch_id, b.parent_id, b.fork, count(c.idx) from branch as b
+                            left join branch_command as c on b.branch_id = c.branch_id
+                            group by b.branch_id order by b.branch_id"
+                    )?;
+                    let branches = stmt.query_map([], |row| Ok(BranchInfo {
+                        id: row.get(0)?, parent_id: row.get(1)?, fork: row.get(2)?, len: row.get::<_, i64>(3)? as usize,
+                    }))?;
+                    branches.collect()
+                })
+            }
+        }
+    }
+
+    // Replace the commands after the current position with the branch. Returns the new max sequence number and the clean point.
+    fn switch_branch(&mut self, branch_id: i64) -> Result<(Option<i64>, Option<i64>), Report<SqliteUndoStoreError>> {
+        match &mut self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, cur_cmd_seq_no, model, conn, .. } => {
+                let db = Db::new(sqlite_path.clone(), conn);
+                let branch: Option<(Option<i64>, i64)> = db.exec(|conn| conn.query_row(
+                    "select parent_id, fork from branch where branch_id = ?1", [branch_id], |row| Ok((row.get(0)?, row.get(1)?))
+                ).optional())?;
+                let fork = match branch {
+                    None => error_stack::bail!(SqliteUndoStoreError::BranchNotFound(branch_id)),
+                    Some((None, fork)) if fork == *cur_cmd_seq_no => fork,
+                    Some(_) => error_stack::bail!(SqliteUndoStoreError::CmdSequenceError),
+                };
+
+                db.exec(|conn| {
+                    let tx = conn.unchecked_transaction()?;
+                    Self::stash_redo_history(&tx, fork)?;
+                    tx.execute(
+                        "update clean_point set seq_no = (select ?2 + 1 + idx from branch_command where branch_id = ?1 and clean)
+                            where exists (select 1 from branch_command where branch_id = ?1 and clean)",
+                        [branch_id, fork]
+                    )?;
+                    tx.execute(
+                        "insert into command (command_id, serialized, compression, raw_size, description, created_at, metadata, grouped)
+                            select ?2 + 1 + idx, serialized, compression, raw_size, description, created_at, metadata, grouped
+                                from branch_command where branch_id = ?1",
+                        [branch_id, fork]
+                    )?;
+                    tx.execute("delete from branch_command where branch_id = ?1", [branch_id])?;
+                    tx.execute("update branch set parent_id = null, fork = fork + ?2 where parent_id = ?1", [branch_id, fork])?;
+                    tx.execute("delete from branch where branch_id = ?1", [branch_id])?;
+                    // Snapshots after the fork belong to the old history.
+                    tx.execute("delete from snapshot where ?1 <= snapshot_id", [fork])?;
+                    tx.commit()
+                })?;
+                let serialized = codec::serialize(self.codec.as_ref(), model).map_err(SqliteUndoStoreError::SerializeError)?;
+                Self::save_snapshot(&db, serialized, fork, self.compression_level, self.cipher.as_deref())?;
+                tracing::trace!("switch_branch() switched to branch {} at {}", branch_id, fork);
+
+                let clean_seq_no = db.exec(|conn| conn.query_row("select seq_no from clean_point", [], |row| row.get(0)))?;
+                Ok((Self::min_max_seq_no(&db)?.map(|(_, max)| max), clean_seq_no))
+            }
+        }
+    }
+
+    fn lock_file_path(base_dir: &std::path::Path) -> std::path::PathBuf {
+        let mut path: std::path::PathBuf = base_dir.to_path_buf();
+        path.push("lock");
+        path
+    }
+
+    // The owner is recorded in a separate file since the locked file cannot be read by other processes on some platforms.
+    fn lock_owner_file_path(base_dir: &std::path::Path) -> std::path::PathBuf {
+        let mut path: std::path::PathBuf = base_dir.to_path_buf();
+        path.push("lock.owner");
+        path
+    }
+
+    fn clear_lock_owner(base_dir: &std::path::Path) -> std::io::Result<()> {
+        match std::fs::remove_file(Self::lock_owner_file_path(base_dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Take an advisory lock of the lock file. The lock is released when the returned file is dropped even if the process crashes.
+    fn try_lock(base_dir: &std::path::Path) -> Result<std::fs::File, Report<SqliteUndoStoreError>> {
+        let lock_file_path = Self::lock_file_path(base_dir);
+        let cannot_lock = |error| SqliteUndoStoreError::CannotLock { path: lock_file_path.clone(), error }.into_report();
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(&lock_file_path).map_err(cannot_lock)?;
+        // Called through the trait so that File::try_lock() of newer std is not picked.
+        if !fs4::fs_std::FileExt::try_lock_exclusive(&file).map_err(cannot_lock)? {
+            return Err(cannot_lock(std::io::ErrorKind::WouldBlock.into()));
+        }
+
+        let owner = LockOwner::current();
+        std::fs::write(Self::lock_owner_file_path(base_dir), owner.to_string()).map_err(cannot_lock)?;
+        Ok(file)
+    }
+
+    // Returns the owner if the lock is held by a live process.
+    fn lock_owner(base_dir: &std::path::Path) -> Result<Option<LockOwner>, Report<SqliteUndoStoreError>> {
+        let lock_file_path = Self::lock_file_path(base_dir);
+        if !lock_file_path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(&lock_file_path).map_err(|e| SqliteUndoStoreError::FileError(lock_file_path.clone(), e))?;
+        match fs4::fs_std::FileExt::try_lock_shared(&file) {
+            Ok(true) => Ok(None),
+            Ok(false) => {
+                // The owner may not have written the file yet.
+                let owner_file_path = Self::lock_owner_file_path(base_dir);
+                let content = match std::fs::read_to_string(&owner_file_path) {
+                    Ok(content) => content,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(e) => error_stack::bail!(SqliteUndoStoreError::FileError(owner_file_path, e)),
+                };
+                Ok(Some(LockOwner::parse(&content)))
+            }
+            Err(error) => Err(SqliteUndoStoreError::CannotLock { path: lock_file_path, error }.into_report()),
+        }
+    }
+
+    // Clear the owner left by a dead process. Fails if a live process holds the lock.
+    // The lock file is kept. Removing it while locked would let another process lock a new file at the same path.
+    fn force_unlock(base_dir: &std::path::Path) -> Result<(), Report<SqliteUndoStoreError>> {
+        let lock_file_path = Self::lock_file_path(base_dir);
+        if !lock_file_path.exists() {
+            return Ok(());
+        }
+        let lock_file = Self::try_lock(base_dir)?;
+        Self::clear_lock_owner(base_dir).map_err(|error| SqliteUndoStoreError::CannotUnlock { path: lock_file_path, error })?;
+        drop(lock_file);
+        Ok(())
+    }
+
+    #[inline]
+    fn open_existing<P: AsRef<std::path::Path>>(path: P) -> rusqlite::Result<rusqlite::Connection> {
+        rusqlite::Connection::open(&path)
+    }
+
+    fn create_new<P: AsRef<std::path::Path>>(path: P) -> rusqlite::Result<rusqlite::Connection> {
+        let conn = rusqlite::Connection::open(&path)?;
+        Self::create_tables(&conn)?;
+        Ok(conn)
+    }
+
+    fn create_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute_batch(
+            "begin;
+            create table command(command_id integer primary key not null, serialized blob not null);
+            create table snapshot(snapshot_id integer primary key not null, serialized blob not null);
+            create table cmd_seq_no(cur_cmd_seq_no integer);
+            create table version(version integer not null);
+            insert into version (version) values (1);
+            commit;"
+        )?;
+        Ok(())
+    }
+
+    // Returns the connection and the lock file. The lock is held while the lock file is alive.
+    fn open_sqlite<P: AsRef<Path>>(dir: P, sqlite_path: PathBuf) -> Result<(Connection, std::fs::File), Report<SqliteUndoStoreError>> {
+        let (conn, lock_file) = if sqlite_path.exists() {
+            if ! dir.as_ref().is_dir() {
+                return Err(Report::from(SqliteUndoStoreError::NotADirectory(dir.as_ref().to_owned())))
+            }
+            let lock_file = Self::try_lock(dir.as_ref())?;
+            (Self::open_existing(&sqlite_path), lock_file)
+        } else {
+            std::fs::create_dir_all(dir.as_ref()).map_err(|e| SqliteUndoStoreError::FileError(dir.as_ref().to_path_buf(), e))?;
+            let lock_file = Self::try_lock(dir.as_ref())?;
+            (Self::create_new(&sqlite_path), lock_file)
+        };
+        let mut conn = conn.map_err(|e|
+            SqliteUndoStoreError::DbError(sqlite_path.clone(), e.into_report())
+        )?;
+        Self::migrate(&sqlite_path, &mut conn, MIGRATIONS)?;
+        Ok((conn, lock_file))
+    }
+
+    // Open without taking the lock. Migration is not possible so that only the current schema version is supported.
+    fn open_sqlite_read_only(sqlite_path: PathBuf) -> Result<Connection, Report<SqliteUndoStoreError>> {
+        if !sqlite_path.exists() {
+            error_stack::bail!(SqliteUndoStoreError::NotFound(sqlite_path));
+        }
+        let conn = Self::db(&sqlite_path, || Connection::open_with_flags(&sqlite_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY))?;
+        let version = Self::db(&sqlite_path, || Self::get_schema_version(&conn))?;
+        if version != SCHEMA_VERSION {
+            error_stack::bail!(SqliteUndoStoreError::UnsupportedSchemaVersion { path: sqlite_path, version, supported: SCHEMA_VERSION });
+        }
+        Ok(conn)
+    }
+
+    fn get_schema_version(conn: &Connection) -> Result<i64, rusqlite::Error> {
+        conn.query_row("select max(version) from version", [], |row| row.get(0))
+    }
+
+    // Apply migrations[version - 1..] one by one, each in its own transaction.
+    fn migrate(sqlite_path: &Path, conn: &mut Connection, migrations: &[Migration]) -> Result<(), Report<SqliteUndoStoreError>> {
+        let supported = migrations.len() as i64 + 1;
+        let version = Self::db(sqlite_path, || Self::get_schema_version(conn))?;
+        if version < 1 || supported < version {
+            error_stack::bail!(SqliteUndoStoreError::UnsupportedSchemaVersion { path: sqlite_path.to_path_buf(), version, supported });
+        }
+
+        for (i, migration) in migrations.iter().enumerate().skip((version - 1) as usize) {
+            let to_version = i as i64 + 2;
+            Self::db(sqlite_path, || {
+                let tx = conn.transaction()?;
+                migration(&tx)?;
+                tx.execute("update version set version = ?1", [to_version])?;
+                tx.commit()
+            })?;
+            tracing::info!("Migrated {:?} to schema version {}.", sqlite_path, to_version);
+        }
+        Ok(())
+    }
+
+    fn get_cur_seq_no(conn: &Connection) -> Result<i64, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "select count(cur_cmd_seq_no), max(cur_cmd_seq_no) from cmd_seq_no"
+        )?;
+        let mut rows = stmt.query([])?;
+                
+        let row = rows.next()?.unwrap();
+        let count: i64 = row.get(0)?;
+        if 1 < count {
+            panic!("Command sequence number inconsistent. The record count({}) should be 0 or 1.", count);
+        } else {
+            let cur_seq: Option<i64> = row.get(1)?;
+            match cur_seq {
+                None if conn.is_readonly(rusqlite::MAIN_DB)? => Ok(0),
+                None => {
+                    conn.execute("insert into cmd_seq_no (cur_cmd_seq_no) values (0)", rusqlite::params![])?;
+                    Ok(0)
+                },
+                Some(seq) => Ok(seq)
+            }
+        }
+    }
+
+    fn restore_model(sqlite_path: &Path, conn: &Connection, codec: &dyn Codec, cipher: Option<&dyn Cipher>) -> Result<(i64, M), Report<SqliteUndoStoreError>> {
+        let cur_seq_no = Self::get_cur_seq_no(conn).map_err(|e| SqliteUndoStoreError::DbError(sqlite_path.to_path_buf(), e.into_report()))?;
+        Ok((cur_seq_no, Self::restore_model_at(sqlite_path, conn, codec, cipher, cur_seq_no)?))
+    }
+
+    // Restore the model at the sequence number from the last snapshot, or from the first command if there is no snapshot.
+    fn restore_model_at(
+        sqlite_path: &Path, conn: &Connection, codec: &dyn Codec, cipher: Option<&dyn Cipher>, cur_seq_no: i64
+    ) -> Result<M, Report<SqliteUndoStoreError>> {
+        match Self::load_last_snapshot(sqlite_path, conn, codec, cipher)? {
+            Some((last_snapshot_id, mut model)) => {
+                tracing::trace!("loading snapshot. Snapshot id: {}, cmd seq no: {}.", last_snapshot_id, cur_seq_no);
+
+                // Restore with snapshot.
+                if cur_seq_no < last_snapshot_id {
+                    let mut stmt = Self::db(
+                        sqlite_path,
+                        || conn.prepare(
+                        "select command_id, serialized, compression from command where ?1 < command_id and command_id <= ?2 order by command_id desc"
+                        )
+                    )?;
+                    let mut rows = Self::db(
+                        sqlite_path,
+                        || stmt.query([cur_seq_no, last_snapshot_id])
+                    )?;
+                            
+                    let mut cmd_id = last_snapshot_id;
+
+                    while let Some(row) = Self::db(sqlite_path, || rows.next())? {
+                        let id: i64 = Self::db(sqlite_path, || row.get(0))?;
+                        tracing::trace!("loading snapshot. cmd id: {}.", id);
+                        if id != cmd_id {
+                            return Err(SqliteUndoStoreError::CannotRestoreModel {
+                                snapshot_id: Some(last_snapshot_id), not_foud_cmd_id: cmd_id
+                            }.into_report())
+                        }
+                        cmd_id -= 1;
+                                
+                        let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
+                        let compression: Option<String> = Self::db(sqlite_path, || row.get(2))?;
+                        let serialized = Self::unpack(sqlite_path, serialized, compression.as_deref(), cipher)?;
+                        let cmd: C = codec::deserialize(codec, &serialized).map_err(|ser_err|
+                            SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
+                        )?;
+                        cmd.try_undo(&mut model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: id, error })?;
+                    }
+                } else if last_snapshot_id < cur_seq_no {
+                    let mut stmt = Self::db(
+                        sqlite_path,
+                        || conn.prepare(
+                            "select command_id, serialized, compression from command where ?1 < command_id and command_id <= ?2 order by command_id asc"
+                        )
+                    )?;
+                    let mut rows = Self::db(sqlite_path, || stmt.query([last_snapshot_id, cur_seq_no]))?;
+                            
+                    let mut cmd_id = last_snapshot_id + 1;
+
+                    while let Some(row) = Self::db(sqlite_path, || rows.next())? {
+                        let id: i64 = Self::db(
+                            sqlite_path, || row.get(0)
+                        )?;
+                        tracing::trace!("loading snapshot. cmd id: {}.", id);
+                        if id != cmd_id {
+                            return Err(SqliteUndoStoreError::CannotRestoreModel {
+                                snapshot_id: Some(last_snapshot_id), not_foud_cmd_id: cmd_id
+                            }.into_report())
+                        }
+                        cmd_id += 1;
+                        
+                        let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
+                        let compression: Option<String> = Self::db(sqlite_path, || row.get(2))?;
+                        let serialized = Self::unpack(sqlite_path, serialized, compression.as_deref(), cipher)?;
+                        let cmd: C = codec::deserialize(codec, &serialized).map_err(|ser_err|
+                            SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
+                        )?;
+                        cmd.try_redo(&mut model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: id, error })?;
+                    }
+                }
+        
+                Ok(model)
+            },
+            None => {
+                // Restore without snapshot.
+                Self::load_without_snapshot(sqlite_path, conn, codec, cipher, cur_seq_no)
+            },
+        }
+    }
+
+    fn load_last_snapshot(sqlite_path: &Path, conn: &Connection, codec: &dyn Codec, cipher: Option<&dyn Cipher>) -> Result<Option<(i64, M)>, Report<SqliteUndoStoreError>> {
+        let mut stmt = Self::db(
+            sqlite_path,
+            || conn.prepare(
+            "select snapshot_id, serialized, compression from snapshot
+                    where
+                      snapshot_id >= (select min(command_id) from command) -1
+                      and snapshot_id <= (select max(command_id) from command)
+                    order by snapshot_id desc limit 1"
+            )
+        )?;
+        let mut rows = Self::db(sqlite_path, || stmt.query([]))?;
+        
+        if let Some(row) = Self::db(sqlite_path, || rows.next())? {
+            let id: i64 = Self::db(sqlite_path, || row.get(0))?;
+            let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
+            let compression: Option<String> = Self::db(sqlite_path, || row.get(2))?;
+            let serialized = Self::unpack(sqlite_path, serialized, compression.as_deref(), cipher)?;
+            let snapshot: M = codec::deserialize(codec, &serialized).map_err(|ser_err|
+                SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
+            )?;
+            Ok(Some((id, snapshot)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn load_without_snapshot(sqlite_path: &Path, conn: &Connection, codec: &dyn Codec, cipher: Option<&dyn Cipher>, cur_seq_no: i64) -> Result<M, Report<SqliteUndoStoreError>> {
+        let mut stmt = Self::db(
+            sqlite_path,
+            || conn.prepare(
+                "select command_id, serialized, compression from command where command_id <= ?1"
+            )
+        )?;
+        let mut rows = Self::db(sqlite_path, || stmt.query([cur_seq_no]))?;
+
+        let mut cmd_id = 1;
+        let mut model = M::default();
+        while let Some(row) = Self::db(sqlite_path, || rows.next())? {
+            let id: i64 = Self::db(sqlite_path, || row.get(0))?;
+            if id != cmd_id {
+                return Err(SqliteUndoStoreError::CannotRestoreModel { snapshot_id: None, not_foud_cmd_id: cmd_id }.into_report())
+            }
+            cmd_id += 1;
+            
+            let serialized: Vec<u8> = Self::db(sqlite_path, || row.get(1))?;
+            let compression: Option<String> = Self::db(sqlite_path, || row.get(2))?;
+            let serialized = Self::unpack(sqlite_path, serialized, compression.as_deref(), cipher)?;
+            let cmd: C = codec::deserialize(codec, &serialized).map_err(|ser_err|
+                SqliteUndoStoreError::CannotDeserialize { path: None, seq_no: id, ser_err }
+            )?;
+            cmd.try_redo(&mut model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: id, error })?;
+        }
+        Ok(model)
+    }
+
+    // Does nothing in read-only mode.
+    fn save_seq_no(sqlite_path: &Path, conn: &Connection, seq_no: i64) -> Result<(), Report<SqliteUndoStoreError>> {
+        if Self::db(sqlite_path, || conn.is_readonly(rusqlite::MAIN_DB))? {
+            return Ok(());
+        }
+        Self::db(
+            sqlite_path,
+            || conn.execute("update cmd_seq_no set cur_cmd_seq_no = ?1", rusqlite::params![seq_no])
+        )?;
+        tracing::trace!("Saved seq no: {}", seq_no);
+        Ok(())
+    }
+
+    fn trim_undo_records(conn: &Connection, undo_limit: usize) -> std::result::Result<usize, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "delete from command as c0 where c0.command_id not in (
+                select command_id from command as c1 order by command_id desc limit ?1
+            )"
+        )?;
+        
+        stmt.execute(rusqlite::params![undo_limit as i64])
+    }
+
+    fn get_last_snapshot_id(conn: &Connection, sqlite_path: &Path) -> Result<Option<i64>, Report<SqliteUndoStoreError>> {
+        let mut stmt = Self::db(
+            sqlite_path,
+            || conn.prepare(
+                "select max(snapshot_id) from snapshot"
+            )
+        )?;
+        let mut rows = Self::db(sqlite_path, || stmt.query([]))?;
+        let row = rows.next().unwrap();
+        Ok(row.unwrap().get(0).unwrap())
+    }
+
+    fn trim_snapshots(conn: &Connection) -> std::result::Result<usize, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "delete from snapshot where snapshot_id < (select max(snapshot_id) from snapshot)"
+        )?;
+        tracing::trace!("Snapshot trimmed.");
+        
+        stmt.execute(rusqlite::params![])
+    }
+
+    fn save_snapshot(
+        db: &Db, ser_model: Vec<u8>, seq_no: i64, compression_level: Option<u32>, cipher: Option<&dyn Cipher>
+    ) -> Result<(), Report<SqliteUndoStoreError>> {
+        let packed = Self::pack(&db.sqlite_path, ser_model, compression_level, cipher)?;
+        db.exec(|conn| {
+            let mut stmt = conn.prepare(
+                "insert into snapshot (snapshot_id, serialized, compression, raw_size) values (?1, ?2, ?3, ?4)"
+            )?;
+            stmt.execute(rusqlite::params![seq_no, packed.data, packed.compression, packed.raw_size])
+        })?;
+        tracing::trace!("Snapshot saved: snapshot id: {}", seq_no);
+        
+        Ok(())
+    }
+
+    // False if the command does not exist.
+    fn is_grouped(db: &Db, seq_no: i64) -> Result<bool, Report<SqliteUndoStoreError>> {
+        let grouped: Option<bool> = db.exec(|conn| conn.query_row(
+            "select grouped from command where command_id = ?1", [seq_no], |row| row.get(0)
+        ).optional())?;
+        Ok(grouped.unwrap_or(false))
+    }
+
+    // Returns the serialized command of the seq no.
+    fn load_cmd(db: &Db, seq_no: i64, cipher: Option<&dyn Cipher>) -> Result<Option<Vec<u8>>, Report<SqliteUndoStoreError>> {
+        let row: Option<(Vec<u8>, Option<String>)> = db.exec(|conn| conn.query_row(
+            "select serialized, compression from command where command_id = ?1", [seq_no], |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional())?;
+        match row {
+            Some((serialized, compression)) => Ok(Some(Self::unpack(&db.sqlite_path, serialized, compression.as_deref(), cipher)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Metadata is encrypted but not compressed since it is supposed to be small.
+    fn pack_metadata(
+        sqlite_path: &Path, metadata: Option<Vec<u8>>, cipher: Option<&dyn Cipher>
+    ) -> Result<Option<Vec<u8>>, Report<SqliteUndoStoreError>> {
+        metadata.map(|metadata| Self::pack(sqlite_path, metadata, None, cipher).map(|packed| packed.data)).transpose()
+    }
+
+    // Descriptions are stored as text, or as an encrypted blob if a cipher is specified.
+    fn pack_description(
+        sqlite_path: &Path, description: Option<String>, cipher: Option<&dyn Cipher>
+    ) -> Result<Value, Report<SqliteUndoStoreError>> {
+        match (description, cipher) {
+            (None, _) => Ok(Value::Null),
+            (Some(description), None) => Ok(Value::Text(description)),
+            (Some(description), Some(_)) => Self::pack(sqlite_path, description.into_bytes(), None, cipher).map(|packed| Value::Blob(packed.data)),
+        }
+    }
+
+    fn unpack_description(
+        sqlite_path: &Path, description: Value, cipher: Option<&dyn Cipher>
+    ) -> Result<Option<String>, Report<SqliteUndoStoreError>> {
+        match description {
+            Value::Text(description) => Ok(Some(description)),
+            Value::Blob(data) => {
+                let data = Self::unpack(sqlite_path, data, None, cipher)?;
+                String::from_utf8(data).map(Some).map_err(|e|
+                    SqliteUndoStoreError::CipherError(sqlite_path.to_path_buf(), Box::new(e)).into_report()
+                )
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Compress and then encrypt the blob to store.
+    fn pack(
+        sqlite_path: &Path, serialized: Vec<u8>, compression_level: Option<u32>, cipher: Option<&dyn Cipher>
+    ) -> Result<compression::Packed, Report<SqliteUndoStoreError>> {
+        let mut packed = compression::pack(serialized, compression_level).map_err(|e| SqliteUndoStoreError::CompressionError(sqlite_path.to_path_buf(), e))?;
+        if let Some(cipher) = cipher {
+            packed.data = cipher.encrypt(&packed.data).map_err(|e| SqliteUndoStoreError::CipherError(sqlite_path.to_path_buf(), e))?;
+        }
+        Ok(packed)
+    }
+
+    fn unpack(
+        sqlite_path: &Path, data: Vec<u8>, compression: Option<&str>, cipher: Option<&dyn Cipher>
+    ) -> Result<Vec<u8>, Report<SqliteUndoStoreError>> {
+        let data = match cipher {
+            Some(cipher) => cipher.decrypt(&data).map_err(|e| SqliteUndoStoreError::CipherError(sqlite_path.to_path_buf(), e))?,
+            None => data,
+        };
+        Ok(compression::unpack(sqlite_path, data, compression)?)
+    }
+
+    fn compression_stats(&mut self) -> Result<CompressionStats, Report<SqliteUndoStoreError>> {
+        match &self.state {
+            PersisterServerState::Idle => {
+                Err(SqliteUndoStoreError::NotOpend.into_report())
+            }
+            PersisterServerState::Loaded { sqlite_path, conn, .. } => {
+                Self::db(sqlite_path, || conn.query_row(
+                    "select coalesce(sum(coalesce(raw_size, length(serialized))), 0), coalesce(sum(length(serialized)), 0) from (
+                        select serialized, raw_size from command union all select serialized, raw_size from snapshot
+                    )",
+                    [],
+                    |row| Ok(CompressionStats { raw_size: row.get(0)?, stored_size: row.get(1)? })
+                ))
+            }
+        }
+    }
+
+    #[inline]
+    fn db<F, T>(sqlite_path: &Path, f: F) -> Result<T, Report<SqliteUndoStoreError>> where F: FnOnce() -> std::result::Result<T, rusqlite::Error> {
+        f().map_err(|e| {
+            SqliteUndoStoreError::DbError(sqlite_path.to_path_buf(), e.into_report()).into_report()
+        })
+    }
+}
+
+/// Process that holds the lock of a store directory.
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockOwner {
+    pub pid: Option<u32>,
+    pub host_name: Option<String>,
+    pub locked_at: Option<std::time::SystemTime>,
+}
+
+#[cfg(feature = "persistence")]
+impl LockOwner {
+    fn current() -> Self {
+        let host_name = std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")).ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_owned()))
+            .filter(|h| !h.is_empty());
+        Self {
+            pid: Some(std::process::id()),
+            host_name,
+            locked_at: Some(std::time::SystemTime::now()),
+        }
+    }
+
+    fn parse(content: &str) -> Self {
+        let mut owner = Self { pid: None, host_name: None, locked_at: None };
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("pid", pid)) => owner.pid = pid.parse().ok(),
+                Some(("host", host)) => owner.host_name = Some(host.to_owned()),
+                Some(("locked_at", secs)) => owner.locked_at = secs.parse().ok().map(|secs| std::time::UNIX_EPOCH + Duration::from_secs(secs)),
+                _ => {},
+            }
+        }
+        owner
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(pid) = self.pid {
+            writeln!(f, "pid={}", pid)?;
+        }
+        if let Some(host_name) = &self.host_name {
+            writeln!(f, "host={}", host_name)?;
+        }
+        if let Some(secs) = self.locked_at.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()) {
+            writeln!(f, "locked_at={}", secs.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "persistence")]
+struct Db<'a> {
+    sqlite_path: PathBuf,
+    conn: &'a Connection,
+}
+
+#[cfg(feature = "persistence")]
+impl<'a> Db<'a> {
+    fn new(sqlite_path: PathBuf, conn: &'a Connection) -> Self {
+        Self { sqlite_path, conn }
+    }
+
+    #[inline]
+    fn exec<F, T>(&self, f: F) -> Result<T, Report<SqliteUndoStoreError>> where F: FnOnce(&Connection) -> std::result::Result<T, rusqlite::Error> {
+        let sqlite_path = self.sqlite_path.clone();
+        f(self.conn).map_err(|e| {
+            SqliteUndoStoreError::DbError(sqlite_path, e.into_report()).into_report()
+        })
+    }
+}
+
+#[cfg(feature = "persistence")]
+pub struct SqliteUndoStore<C, M, E>
+  where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
+{
+    phantom: std::marker::PhantomData<C>,
+    phantome: std::marker::PhantomData<E>,
+    model: M,
+    options: Options<M>,
+    persister_client: PersisterClient,
+    base_dir: std::path::PathBuf,
+    // The last added command and when it was added. Kept only while merging is enabled.
+    last_cmd: Option<(Instant, C)>,
+    read_only: bool,
+    codec: Arc<dyn Codec>,
+    // Descriptions of the commands to be undone and redone.
+    undo_description: Option<String>,
+    redo_description: Option<String>,
+    // Sequence number of the command that undo_description is taken from while commands are added.
+    undo_description_seq_no: i64,
+    // Recorded with the commands added.
+    metadata: Option<Vec<u8>>,
+    group: GroupState,
+}
+
+pub const SQLITE_FILE_NAME: &str = "db.sqlite";
+pub const DEFAULT_UNDO_LIMIT: usize = 100;
+
+pub struct Options<M> {
+    pub undo_limit: usize,
+    pub merge_timeout: Option<Duration>,
+
+    /// If true, the redo history is kept as a branch when a command is added after undo.
+    pub undo_tree: bool,
+
+    /// If specified, the store is compacted when the sequence number of a new command reaches this value.
+    pub auto_compaction_threshold: Option<i64>,
+
+    /// Called when a snapshot is restored. If you have states that are out of scope to manage undo/redo operations, you can restore them here.
+    pub on_snapshot_restored: Option<Box<dyn FnOnce(M) -> M>>,
+
+    /// Called when a command cannot be persisted in background. If not specified, errors are queued. See SqliteUndoStore::pending_errors().
+    #[cfg(feature = "persistence")]
+    pub on_persist_error: Option<Box<dyn FnMut(PersistError)>>,
+
+    /// Observers registered on open so that they are notified of the snapshot restored on open as well. See UndoStore::subscribe().
+    pub observers: Vec<Observer>,
+
+    /// Codec to store commands and snapshots in a new database. An existing database is read with the recorded codec.
+    #[cfg(feature = "persistence")]
+    pub codec: Arc<dyn Codec>,
+
+    /// Compression level(0-9) of commands and snapshots stored. Not compressed if None.
+    #[cfg(feature = "compression")]
+    pub compression_level: Option<u32>,
+
+    /// Cipher to encrypt commands and snapshots stored. Not encrypted if None.
+    #[cfg(feature = "persistence")]
+    pub cipher: Option<Arc<dyn Cipher>>,
+}
+
+// Compaction renumbers the commands from 1, so the next command gets the undo limit + 1 at most.
+fn min_auto_compaction_threshold(undo_limit: usize) -> i64 {
+    i64::try_from(undo_limit).unwrap_or(i64::MAX).saturating_add(2)
+}
+
+impl<M> Default for Options<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M> Options<M> {
+    pub fn new() -> Self {
+        Self {
+            undo_limit: DEFAULT_UNDO_LIMIT,
+            merge_timeout: None,
+            undo_tree: false,
+            auto_compaction_threshold: None,
+            on_snapshot_restored: None,
+            #[cfg(feature = "persistence")]
+            on_persist_error: None,
+            observers: vec![],
+            #[cfg(feature = "persistence")]
+            codec: Arc::new(codec::BincodeCodec),
+            #[cfg(feature = "compression")]
+            compression_level: None,
+            #[cfg(feature = "persistence")]
+            cipher: None,
+        }
+    }
+
+    pub fn with_undo_limit(self, l: usize) -> Self {
+        Self {
+            undo_limit: l,
+            ..self
+        }
+    }
+
+    pub fn with_merge_timeout(self, timeout: Duration) -> Self {
+        Self {
+            merge_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Keep the redo history as a branch instead of discarding it when a command is added after undo.
+    pub fn with_undo_tree(self) -> Self {
+        Self {
+            undo_tree: true,
+            ..self
+        }
+    }
+
+    /// Compact the store automatically when the sequence number of a new command reaches the threshold.
+    /// The threshold is raised to the undo limit + 2 if it is smaller, otherwise every command would trigger compaction.
+    /// It is checked again when a command is added in case the undo limit is changed later.
+    /// Specify MAX_COMMAND_ID to compact only when it is inevitable.
+    pub fn with_auto_compaction(self, threshold: i64) -> Self {
+        Self {
+            auto_compaction_threshold: Some(threshold.max(min_auto_compaction_threshold(self.undo_limit))),
+            ..self
+        }
+    }
+
+    pub fn with_on_snapshot_restored(self, on_snapshot_restored: Box<dyn FnOnce(M) -> M>) -> Self {
+        Self {
+            on_snapshot_restored: Some(on_snapshot_restored),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn with_on_persist_error(self, on_persist_error: Box<dyn FnMut(PersistError)>) -> Self {
+        Self {
+            on_persist_error: Some(on_persist_error),
+            ..self
+        }
+    }
+
+    pub fn with_observer(mut self, observer: Observer) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Use the codec such as codec::JsonCodec instead of bincode. A user-provided codec should be specified every time the store is opened.
+    #[cfg(feature = "persistence")]
+    pub fn with_codec(self, codec: Arc<dyn Codec>) -> Self {
+        Self {
+            codec,
+            ..self
+        }
+    }
+
+    /// Compress commands and snapshots stored with zlib. The level is from 0(fastest) to 9(smallest).
+    /// Opening the store fails with SqliteUndoStoreError::InvalidCompressionLevel if the level is out of range.
+    /// Rows stored without compression can still be read.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(self, level: u32) -> Self {
+        Self {
+            compression_level: Some(level),
+            ..self
+        }
+    }
+
+    /// Encrypt commands and snapshots stored. The cipher is fixed when the first command is stored.
+    /// Opening the database with another key fails with SqliteUndoStoreError::WrongKey.
+    #[cfg(feature = "persistence")]
+    pub fn with_cipher(self, cipher: Arc<dyn Cipher>) -> Self {
+        Self {
+            cipher: Some(cipher),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl<C, M, E> SqliteUndoStore<C, M, E> where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned {
+    pub fn dir(&self) -> &std::path::PathBuf {
+        &self.base_dir
+    }
+
+    // Open the specified directory or newly create it if that does not exist.
+    pub fn open<P: AsRef<Path>>(dir: P, options: Options<M>) -> Result<Self, Report<SqliteUndoStoreError>>
+        where C: crate::cmd::SerializableCmd<Model = M> + serde::de::DeserializeOwned
+    {
+        Self::open_with_mode(dir, options, false)
+    }
+
+    /// Open the existing directory without taking the lock so that it can be inspected while another process holds it.
+    /// You can undo/redo but the cursor is not written back. Commands cannot be added.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P, options: Options<M>) -> Result<Self, Report<SqliteUndoStoreError>>
+        where C: crate::cmd::SerializableCmd<Model = M> + serde::de::DeserializeOwned
+    {
+        Self::open_with_mode(dir, options, true)
+    }
+
+    fn open_with_mode<P: AsRef<Path>>(dir: P, mut options: Options<M>, read_only: bool) -> Result<Self, Report<SqliteUndoStoreError>> {
+        let (cmd_sender, cmd_receiver) = mpsc::channel();
+        let (resp_sender, resp_receiver) = mpsc::channel();
+        
+        let undo_limit = options.undo_limit;
+        let codec = options.codec.clone();
+        #[cfg(feature = "compression")]
+        let compression_level = options.compression_level;
+        #[cfg(feature = "compression")]
+        if let Some(level) = compression_level.filter(|level| 9 < *level) {
+            error_stack::bail!(SqliteUndoStoreError::InvalidCompressionLevel(level));
+        }
+        #[cfg(not(feature = "compression"))]
+        let compression_level = None;
+        let cipher = options.cipher.clone();
+        let undo_tree = options.undo_tree;
+        thread::spawn(move || {
+            let persister_server: PersisterServer<C, M, E> = PersisterServer::new(
+                cmd_receiver, resp_sender, undo_limit, codec, compression_level, cipher, undo_tree,
+            );
+            persister_server.start();
+        });
+
+        let mut observers = Observers::default();
+        for observer in options.observers.drain(..) {
+            observers.subscribe(observer);
+        }
+        let (persister_client, serialized_model, codec) = PersisterClient::open(
+            resp_receiver, cmd_sender, dir.as_ref().to_path_buf(), read_only, options.undo_limit, options.on_persist_error.take(), observers,
+        )?;
+        let model: M = codec::deserialize(codec.as_ref(), &serialized_model).map_err(|e|
+            SqliteUndoStoreError::CannotDeserialize {
+                path: Some(dir.as_ref().to_path_buf()), seq_no: persister_client.last_seq_no, ser_err: e
+            }
+        )?;
+
+        let model = if let Some(f) = options.on_snapshot_restored.take() {
+            f(model)
+        } else { model };
+
+        let mut store = SqliteUndoStore {
+            base_dir: dir.as_ref().to_path_buf(), model,
+            phantom: std::marker::PhantomData, phantome: std::marker::PhantomData,
+            options, persister_client, last_cmd: None, read_only, codec,
+            undo_description: None, redo_description: None, undo_description_seq_no: 0, metadata: None, group: GroupState::default(),
+        };
+        store.load_descriptions()?;
+        let seq_no = store.persister_client.last_seq_no;
+        store.persister_client.observers.notify(StoreEvent::SnapshotRestored { seq_no });
+
+        Ok(store)
+    }
+
+    /// Create a new store in the directory from the model and the history of the in-memory store so that its commands can be
+    /// undone/redone after "Save as". The sequence numbers, the position and the clean point are kept. If the history exceeds
+    /// the undo limit, the oldest commands are dropped first, then the redo history. Branches are not converted.
+    /// Fails with SqliteUndoStoreError::StoreExists if the directory already has a store.
+    pub fn create_from<P: AsRef<Path>>(dir: P, options: Options<M>, store: &InMemoryUndoStore<C, M, E>) -> Result<Self, Report<SqliteUndoStoreError>>
+        where C: crate::cmd::SerializableCmd<Model = M> + serde::de::DeserializeOwned
+    {
+        let undo_limit = options.undo_limit;
+        let mut sqlite = Self::open(dir, options)?;
+        let codec = sqlite.codec.clone();
+        let deserialize_model = |serialized: &[u8], seq_no: i64| -> Result<M, Report<SqliteUndoStoreError>> {
+            Ok(codec::deserialize(codec.as_ref(), serialized).map_err(|ser_err| SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err })?)
+        };
+
+        // Undo a copy of the model back to the first command to convert.
+        let location = store.location;
+        let skip = store.store.len().saturating_sub(undo_limit).min(location);
+        let end = store.store.len().min(skip + undo_limit);
+        let current = codec::serialize(codec.as_ref(), store.history_model()).map_err(SqliteUndoStoreError::SerializeError)?;
+        let mut model = deserialize_model(&current, store.removed_count + location as i64)?;
+        for i in (skip..location).rev() {
+            store.store[i].cmd.try_undo(&mut model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no: store.removed_count + i as i64 + 1, error })?;
+        }
+        let first_seq_no = store.removed_count + skip as i64;
+        let serialized_model = codec::serialize(codec.as_ref(), &model).map_err(SqliteUndoStoreError::SerializeError)?;
+        let clean_seq_no = store.clean_seq_no.filter(|seq_no| (first_seq_no..=first_seq_no + (end - skip) as i64).contains(seq_no));
+        sqlite.persister_client.init(first_seq_no, serialized_model, clean_seq_no)?;
+
+        for entry in store.store.range(skip..end) {
+            let serialized = codec::serialize(codec.as_ref(), &entry.cmd).map_err(SqliteUndoStoreError::SerializeError)?;
+            sqlite.persister_client.add_command(serialized, sqlite.cmd_attrs(), entry.grouped)?;
+        }
+        let (last_seq_no, seq_no) = (sqlite.persister_client.last_seq_no, first_seq_no + (location - skip) as i64);
+        if seq_no < last_seq_no {
+            sqlite.persister_client.go_to(seq_no)?;
+            sqlite.persister_client.observers.notify_moved(last_seq_no, seq_no);
+        }
+        sqlite.model = deserialize_model(&current, seq_no)?;
+        sqlite.load_descriptions()?;
+        // The commands above are persisted in background. Their errors have been received by the calls above
+        // and either queued or passed to the handler.
+        sqlite.persister_client.ensure_persisted()?;
+        Ok(sqlite)
+    }
+
+    /// Replace the model and the history of the in-memory store with the ones retained by this store so that they can be
+    /// undone/redone without the database. The options of the in-memory store are kept. See InMemoryUndoStore::load_from()
+    /// for the commands exceeding its capacity. Branches are not exported.
+    pub fn export_to(&mut self, store: &mut InMemoryUndoStore<C, M, E>) -> Result<(), Report<SqliteUndoStoreError>> {
+        let seq_no = self.persister_client.last_seq_no;
+        let serialized = codec::serialize(self.codec.as_ref(), &self.model).map_err(SqliteUndoStoreError::SerializeError)?;
+        let model: M = codec::deserialize(self.codec.as_ref(), &serialized).map_err(|ser_err|
+            SqliteUndoStoreError::CannotDeserialize { path: None, seq_no, ser_err }
+        )?;
+        let first_seq_no = *self.persister_client.seq_no_range().start();
+        let cmds = self.history()?.map(|entry| (entry.cmd, entry.grouped)).collect();
+        store.replace_history(model, cmds, (seq_no - first_seq_no) as usize, first_seq_no, self.persister_client.clean_seq_no);
+        Ok(())
+    }
+
+    /// Returns the owner if a live process holds the lock of the directory.
+    pub fn lock_owner<P: AsRef<Path>>(dir: P) -> Result<Option<LockOwner>, Report<SqliteUndoStoreError>> {
+        PersisterServer::<C, M, E>::lock_owner(dir.as_ref())
+    }
+
+    /// Clear the owner recorded in the lock file of the directory. The lock is an advisory lock of the OS so a lock of a crashed process is released automatically.
+    /// This is useful to clean up the owner left behind. The file itself is kept. Fails with SqliteUndoStoreError::CannotLock if a live process holds the lock.
+    pub fn force_unlock<P: AsRef<Path>>(dir: P) -> Result<(), Report<SqliteUndoStoreError>> {
+        PersisterServer::<C, M, E>::force_unlock(dir.as_ref())
+    }
+
+    pub fn save_as<P: AsRef<Path>>(&mut self, save_to: P) -> Result<(), Report<SqliteUndoStoreError>> {
+        let mut to = save_to.as_ref().to_path_buf();
+        to.push(SQLITE_FILE_NAME);
+
+        let mut from = self.base_dir.clone();
+        from.push(SQLITE_FILE_NAME);
+
+        std::fs::copy(&from, &to).map_err(|error| Report::from(SqliteUndoStoreError::CannotCopyStore { from: from.clone(), to: to.clone(), error }))?;
+        Ok(())
+    }
+
+    fn _add_cmd(&mut self, cmd: C) -> Result<(), Report<SqliteUndoStoreError>> {
+        if let Some(threshold) = self.options.auto_compaction_threshold {
+            // The field may be set directly.
+            let threshold = threshold.max(min_auto_compaction_threshold(self.options.undo_limit));
+            if threshold <= self.persister_client.last_seq_no + 1 {
+                self.compact()?;
+            }
+        }
+
+        let now = Instant::now();
+        let last_cmd = self.last_cmd.take();
+        if let Some((last_added, last_cmd)) = last_cmd {
+            if self.persister_client.can_undo() && is_within_merge_timeout(self.options.merge_timeout, Some(last_added), now) {
+                if let Some(merged) = last_cmd.merge(&cmd) {
+                    let serialized: Vec<u8> = codec::serialize(self.codec.as_ref(), &merged).map_err(
+                        SqliteUndoStoreError::SerializeError
+                    )?;
+                    self.persister_client.merge_command(serialized, self.cmd_attrs())?;
+                    let seq_no = self.persister_client.last_seq_no;
+                    self.persister_client.observers.notify(StoreEvent::CmdAdded { seq_no, merged: true });
+                    // Keep the description taken from a preceding command in the group.
+                    if self.undo_description.is_none() || self.undo_description_seq_no == seq_no {
+                        (self.undo_description, self.undo_description_seq_no) = (merged.describe(), seq_no);
+                    }
+                    self.redo_description = None;
+                    self.last_cmd = Some((now, merged));
+                    return self.persister_client.process_resp();
+                }
+            }
+        }
+
+        let serialized: Vec<u8> = codec::serialize(self.codec.as_ref(), &cmd).map_err(
+            SqliteUndoStoreError::SerializeError
+        )?;
+
+        let grouped = self.group.add_cmd();
+        self.persister_client.add_command(serialized, self.cmd_attrs(), grouped)?;
+        // A group is described by its first described command.
+        if !grouped || self.undo_description.is_none() {
+            (self.undo_description, self.undo_description_seq_no) = (cmd.describe(), self.persister_client.last_seq_no);
+        }
+        self.redo_description = None;
+        if self.options.merge_timeout.is_some() {
+            self.last_cmd = Some((now, cmd));
+        }
+        self.persister_client.process_resp()
+    }
+
+    fn cmd_attrs(&self) -> CmdAttrs {
+        let created_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0);
+        CmdAttrs { created_at, metadata: self.metadata.clone() }
+    }
+
+    fn load_descriptions(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        (self.undo_description, self.redo_description) = self.persister_client.descriptions()?;
+        Ok(())
+    }
+
+    /// Returns the codec in use. This is the one recorded in the database, which may differ from the one specified in Options.
+    pub fn codec(&self) -> &dyn Codec {
+        self.codec.as_ref()
+    }
+
+    /// Returns the sizes of the stored commands and snapshots to see how well they are compressed.
+    pub fn compression_stats(&mut self) -> Result<CompressionStats, Report<SqliteUndoStoreError>> {
+        self.persister_client.compression_stats()
+    }
+
+    /// Metadata such as the author or the session id recorded with the commands added after this call. A merged command takes the current one.
+    pub fn set_metadata(&mut self, metadata: Option<Vec<u8>>) {
+        self.metadata = metadata;
+    }
+
+    /// Returns when and with what metadata the commands in the history were recorded from the oldest one.
+    pub fn cmd_records(&mut self) -> Result<Vec<CmdRecord>, Report<SqliteUndoStoreError>> {
+        self.persister_client.cmd_records()
+    }
+
+    /// Returns the commands in the history from the oldest one.
+    pub fn history(&mut self) -> Result<impl Iterator<Item = HistoryEntry<C>>, Report<SqliteUndoStoreError>> {
+        let last_seq_no = self.persister_client.last_seq_no;
+        let entries = self.persister_client.history()?.into_iter().map(|(seq_no, ser_cmd, grouped)| {
+            let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+                SqliteUndoStoreError::CannotDeserialize { path: Some(self.base_dir.clone()), seq_no, ser_err }
+            )?;
+            Ok(HistoryEntry { seq_no, undoable: seq_no <= last_seq_no, grouped, cmd })
+        }).collect::<Result<Vec<_>, Report<SqliteUndoStoreError>>>()?;
+        Ok(entries.into_iter())
+    }
+
+    /// Returns all the branches including nested ones in the order of creation. See Options::with_undo_tree().
+    pub fn branches(&mut self) -> Result<Vec<BranchInfo>, Report<SqliteUndoStoreError>> {
+        self.persister_client.branches()
+    }
+
+    /// Move to the tip of the branch. The current redo history becomes a new branch.
+    pub fn switch_branch(&mut self, branch_id: i64) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.ensure_writable()?;
+        // Each branch in the path forks from the current history after switching to its parent.
+        loop {
+            let branches = self.persister_client.branches()?;
+            let find = |id: i64| branches.iter().find(|b| b.id == id).ok_or_else(|| SqliteUndoStoreError::BranchNotFound(id).into_report());
+            let mut branch = find(branch_id)?;
+            while let Some(parent_id) = branch.parent_id {
+                branch = find(parent_id)?;
+            }
+            let (id, fork) = (branch.id, branch.fork);
+
+            let start = self.persister_client.last_seq_no;
+            while fork < self.persister_client.last_seq_no {
+                if !self.persister_client.can_undo() {
+                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
+                }
+                self._undo()?;
+            }
+            while self.persister_client.last_seq_no < fork {
+                if !self.persister_client.can_redo() {
+                    error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo);
+                }
+                self._redo()?;
+            }
+            self.persister_client.observers.notify_moved(start, fork);
+            let max_seq_no = *self.persister_client.seq_no_range().end();
+            self.persister_client.switch_branch(id)?;
+            if fork < max_seq_no {
+                self.persister_client.observers.notify(StoreEvent::HistoryTruncated { seq_nos: fork + 1..=max_seq_no });
+            }
+            while self.persister_client.can_redo() {
+                self._redo()?;
+            }
+            let seq_no = self.persister_client.last_seq_no;
+            self.persister_client.observers.notify_moved(fork, seq_no);
+            if id == branch_id {
+                return self.load_descriptions();
+            }
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn ensure_writable(&self) -> Result<(), Report<SqliteUndoStoreError>> {
+        if self.read_only {
+            error_stack::bail!(SqliteUndoStoreError::ReadOnly(self.base_dir.clone()));
+        }
+        // Checked before the model is mutated.
+        self.persister_client.ensure_persisted()
+    }
+
+    /// True if all the commands are stored. Fails with SqliteUndoStoreError::PersistFailed once a command failed to persist
+    /// even if the error has been taken by pending_errors() or passed to the handler.
+    pub fn saved(&mut self) -> Result<bool, Report<SqliteUndoStoreError>> {
+        if self.read_only {
+            return Ok(true);
+        }
+        self.persister_client.process_resp()?;
+        self.persister_client.saved()
+    }
+
+    /// Renumber the retained commands and snapshots so that sequence numbers start with 1 again.
+    /// Call this when SqliteUndoStoreError::NeedCompaction is reported, or use Options::with_auto_compaction().
+    pub fn compact(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.ensure_writable()?;
+        self.persister_client.compact()
+    }
+
+    /// Returns errors occurred while commands are persisted in background since the last call.
+    /// If a handler is registered by Options::with_on_persist_error(), errors are passed to it instead.
+    pub fn pending_errors(&mut self) -> Result<Vec<PersistError>, Report<SqliteUndoStoreError>> {
+        self.persister_client.process_resp()?;
+        Ok(std::mem::take(&mut self.persister_client.errors))
+    }
+
+    pub fn wait_until_saved(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        while !self.saved()? {
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    // Undo/redo one by one to the sequence number to revert a failed operation. Best effort.
+    fn step_back_to(&mut self, seq_no: i64) {
+        while self.persister_client.last_seq_no < seq_no {
+            if self._redo().is_err() {
+                return;
+            }
+        }
+        while seq_no < self.persister_client.last_seq_no {
+            if self._undo().is_err() {
+                return;
+            }
+        }
+    }
+
+    // Returns true if the next command to undo belongs to the same group.
+    fn _undo(&mut self) -> Result<bool, Report<SqliteUndoStoreError>> {
+        self.last_cmd = None;
+        self.group.has_cmd = false;
+        let (seq_no, ser_cmd, grouped) = self.persister_client.undo()?;
+        let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+            SqliteUndoStoreError::CannotDeserialize {
+                path: Some(self.base_dir.clone()), seq_no, ser_err
+            }
+        )?;
+        if let Err(error) = cmd.try_undo(&mut self.model) {
+            // Move the persister back. Best effort.
+            let _ = self.persister_client.redo();
+            error_stack::bail!(SqliteUndoStoreError::CmdFailed { seq_no, error });
+        }
+        Ok(grouped)
+    }
+
+    // Returns true if the next command to redo belongs to the same group.
+    fn _redo(&mut self) -> Result<bool, Report<SqliteUndoStoreError>> {
+        self.last_cmd = None;
+        self.group.has_cmd = false;
+        let (seq_no, ser_cmd, grouped) = self.persister_client.redo()?;
+        let cmd: C = codec::deserialize(self.codec.as_ref(), &ser_cmd).map_err(|ser_err|
+            SqliteUndoStoreError::CannotDeserialize {
+                path: Some(self.base_dir.clone()), seq_no, ser_err
+            }
+        )?;
+        if let Err(error) = cmd.try_redo(&mut self.model) {
+            let _ = self.persister_client.undo();
+            error_stack::bail!(SqliteUndoStoreError::CmdFailed { seq_no: seq_no + 1, error });
+        }
+        Ok(grouped)
+    }
+}
+
+pub const MAX_COMMAND_ID: i64 = 9_223_372_036_854_775_807;
+
+#[cfg(feature = "persistence")]
+type Migration = fn(&rusqlite::Transaction) -> rusqlite::Result<()>;
+
+// MIGRATIONS[i] migrates the schema from version i + 1 to i + 2. Never modify released migrations, just append new ones.
+#[cfg(feature = "persistence")]
+const MIGRATIONS: &[Migration] = &[
+    add_metadata_table, add_compression_columns, add_branch_tables, add_description_columns, add_cmd_attr_columns,
+    add_grouped_columns, add_clean_point_table, add_branch_clean_column,
+];
+
+// Version 2: Key-value table such as the codec name.
+#[cfg(feature = "persistence")]
+fn add_metadata_table(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("create table metadata(key text primary key not null, value text not null);")
+}
+
+// Version 3: Compression of each row. Null if the row is not compressed.
+#[cfg(feature = "persistence")]
+fn add_compression_columns(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table command add column compression text;
+        alter table command add column raw_size integer;
+        alter table snapshot add column compression text;
+        alter table snapshot add column raw_size integer;"
+    )
+}
+
+// Version 4: Branches of the undo tree. The fork of a nested branch is relative to its parent.
+#[cfg(feature = "persistence")]
+fn add_branch_tables(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table branch(branch_id integer primary key not null, parent_id integer, fork integer not null);
+        create table branch_command(
+            branch_id integer not null, idx integer not null, serialized blob not null, compression text, raw_size integer,
+            primary key(branch_id, idx)
+        );"
+    )
+}
+
+// Version 5: Cmd::describe() of each command. Null if not described or recorded before this version.
+#[cfg(feature = "persistence")]
+fn add_description_columns(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table command add column description text;
+        alter table branch_command add column description text;"
+    )
+}
+
+// Version 6: When each command was recorded in milliseconds since the Unix epoch and the metadata supplied by the user.
+#[cfg(feature = "persistence")]
+fn add_cmd_attr_columns(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table command add column created_at integer;
+        alter table command add column metadata blob;
+        alter table branch_command add column created_at integer;
+        alter table branch_command add column metadata blob;"
+    )
+}
+
+// Version 7: Whether each command is undone/redone together with the previous one.
+#[cfg(feature = "persistence")]
+fn add_grouped_columns(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table command add column grouped integer not null default 0;
+        alter table branch_command add column grouped integer not null default 0;"
+    )
+}
+
+// Version 8: Sequence number marked clean by UndoStore::mark_clean(). Null if it is no longer in the history.
+// An existing database is regarded as clean at the current position.
+#[cfg(feature = "persistence")]
+fn add_clean_point_table(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table clean_point(seq_no integer);
+        insert into clean_point (seq_no) select coalesce(max(cur_cmd_seq_no), 0) from cmd_seq_no;"
+    )
+}
+
+// Version 9: Whether the state after the command is the clean point, which moves with the redo history set aside as a branch.
+#[cfg(feature = "persistence")]
+fn add_branch_clean_column(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("alter table branch_command add column clean integer not null default 0;")
+}
+
+/// Schema version of the SQLite database that this library creates.
+#[cfg(feature = "persistence")]
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64 + 1;
+
+// #[cfg(feature = "persistence")]
+// impl<C, M, E> SqliteUndoStore<C, M, E>
+//   where M: Default + serde::Serialize + serde::de::DeserializeOwned + 'static, C: crate::cmd::SerializableCmd<Model = M>
+// {
+// }
+
+#[cfg(feature = "persistence")]
+impl<C, M, E> UndoStore for SqliteUndoStore<C, M, E>
+  where M: Default + serde::Serialize + serde::de::DeserializeOwned + 'static, C: crate::cmd::SerializableCmd<Model = M>
+{
+    type ModelType = M;
+    type CmdType = C;
+    type ErrType = E;
+    type StoreErrType = SqliteUndoStoreError;
+
+    fn model(&self) -> &M { &self.model }
+
+    fn try_mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<Result<(), Self::ErrType>, Report<SqliteUndoStoreError>> {
+        self.ensure_writable()?;
+        match f(&mut self.model) {
+            Ok(cmd) => {
+                self._add_cmd(cmd)?;
+                Ok(Ok(()))
+            }
+            Err(err) => {
+                Ok(Err(err))
+            }
+        }
+    }
+
+    fn try_add_cmd(&mut self, cmd: Self::CmdType) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.ensure_writable()?;
+        let seq_no = self.persister_client.last_seq_no + 1;
+        cmd.try_redo(&mut self.model).map_err(|error| SqliteUndoStoreError::CmdFailed { seq_no, error })?;
+        self._add_cmd(cmd)
+    }
+
+    // Commands in the group are undone together. Moves back to the original position if a command fails.
+    fn try_undo(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        if self.can_undo() {
+            let start = self.persister_client.last_seq_no;
+            loop {
+                match self._undo() {
+                    Ok(true) if self.can_undo() => {}
+                    Ok(_) => break,
+                    Err(err) => {
+                        self.step_back_to(start);
+                        return Err(err);
+                    }
+                }
+            }
+            let seq_no = self.persister_client.last_seq_no;
+            self.persister_client.observers.notify_moved(start, seq_no);
+            self.load_descriptions()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn can_undo(&self) -> bool {
+        self.persister_client.can_undo()
+    }
+
+    fn can_redo(&self) -> bool {
+        self.persister_client.can_redo()
+    }
+
+    fn try_redo(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        if self.can_redo() {
+            let start = self.persister_client.last_seq_no;
+            loop {
+                match self._redo() {
+                    Ok(true) if self.can_redo() => {}
+                    Ok(_) => break,
+                    Err(err) => {
+                        self.step_back_to(start);
+                        return Err(err);
+                    }
+                }
+            }
+            let seq_no = self.persister_client.last_seq_no;
+            self.persister_client.observers.notify_moved(start, seq_no);
+            self.load_descriptions()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn seq_no(&self) -> i64 {
+        self.persister_client.last_seq_no
+    }
+
+    fn seq_no_range(&self) -> std::ops::RangeInclusive<i64> {
+        self.persister_client.seq_no_range()
+    }
+
+    // The commands in between are always undone/redone on the model instead of restoring a snapshot, so that the states out of
+    // scope of undo/redo are kept.
+    fn try_go_to(&mut self, seq_no: i64) -> Result<(), Report<SqliteUndoStoreError>> {
+        if !self.seq_no_range().contains(&seq_no) {
+            error_stack::bail!(SqliteUndoStoreError::SeqNoOutOfRange(seq_no));
+        }
+        self.last_cmd = None;
+        self.group.has_cmd = false;
+        let from = self.persister_client.last_seq_no;
+        let serialized_commands = self.persister_client.go_to(seq_no)?;
+        let first_seq_no = from.min(seq_no) + 1;
+        let cmds = serialized_commands.iter().enumerate().map(|(i, ser_cmd)|
+            codec::deserialize::<C>(self.codec.as_ref(), ser_cmd).map_err(|ser_err|
+                SqliteUndoStoreError::CannotDeserialize { path: Some(self.base_dir.clone()), seq_no: first_seq_no + i as i64, ser_err }
+            )
+        ).collect::<Result<Vec<_>, _>>()?;
+        if let Err((i, error)) = apply_cmds(&cmds, &mut self.model, seq_no < from) {
+            // Move the persister back. Best effort.
+            let _ = self.persister_client.go_to(from);
+            error_stack::bail!(SqliteUndoStoreError::CmdFailed { seq_no: first_seq_no + i as i64, error });
+        }
+        self.persister_client.observers.notify_moved(from, seq_no);
+        self.load_descriptions()
+    }
+
+    fn begin_group(&mut self) {
+        if self.group.begin() {
+            // Do not merge commands across the group boundary.
+            self.last_cmd = None;
+        }
+    }
+
+    fn end_group(&mut self) {
+        if self.group.end() {
+            self.last_cmd = None;
+        }
+    }
+
+    fn undo_description(&self) -> Option<String> {
+        self.undo_description.clone()
+    }
+
+    fn redo_description(&self) -> Option<String> {
+        self.redo_description.clone()
+    }
+
+    fn irreversible_mutate<R>(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> R>) -> R {
+        f(&mut self.model)
+    }
+
+    fn try_mark_clean(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
+        self.ensure_writable()?;
+        self.persister_client.mark_clean()
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.persister_client.clean_seq_no != Some(self.persister_client.last_seq_no)
+    }
+
+    fn subscribe(&mut self, observer: Observer) -> SubscriptionId {
+        self.persister_client.observers.subscribe(observer)
+    }
+
+    fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.persister_client.observers.unsubscribe(id)
+    }
+}
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::derivable_impls)]
+mod tests {
+    use std::time::Duration;
+    use super::{Cmd, InMemoryUndoStore, UndoStore};
+
+    #[derive(PartialEq, Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum SumCmd {
+        Add(i32), Sub(i32),
+    }
+
+    #[derive(PartialEq, Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    struct Sum(i32);
+
+    impl Default for Sum {
+        fn default() -> Self {
+            Self(0)
+        }
+    }
+
+    impl Cmd for SumCmd {
+        type Model = Sum;
+
+        fn redo(&self, model: &mut Self::Model) {
+            match self {
+                SumCmd::Add(i) => model.0 += *i,
+                SumCmd::Sub(i) => model.0 -= *i,
+            }
+        }
+
+        fn undo(&self, model: &mut Self::Model) {
+            match self {
+                SumCmd::Add(i) => model.0 -= *i,
+                SumCmd::Sub(i) => model.0 += *i,
+            }
+        }
+
+        fn merge(&self, other: &Self) -> Option<Self> {
+            match (self, other) {
+                (SumCmd::Add(i), SumCmd::Add(j)) => Some(SumCmd::Add(i + j)),
+                (SumCmd::Sub(i), SumCmd::Sub(j)) => Some(SumCmd::Sub(i + j)),
+                _ => None,
+            }
+        }
+
+        // Leave Sub undescribed to test commands without descriptions.
+        fn describe(&self) -> Option<String> {
+            match self {
+                SumCmd::Add(i) => Some(format!("Add {}", i)),
+                SumCmd::Sub(_) => None,
+            }
+        }
+    }
+
+    trait Model {
+        type Resp;
+        fn add(&mut self, to_add: i32) -> Self::Resp;
+        fn sub(&mut self, to_sub: i32) -> Self::Resp;
+    }
+
+    impl Model for InMemoryUndoStore<SumCmd, Sum, ()> {
+        type Resp = ();
+
+        fn add(&mut self, to_add: i32) {
+            self.add_cmd(SumCmd::Add(to_add));
+        }
+
+        fn sub(&mut self, to_sub: i32) {
+            self.add_cmd(SumCmd::Sub(to_sub));
+        }
+    }
+
+    #[test]
+    fn can_undo_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        assert_eq!(store.can_undo(), false);
+        store.add(3);
+        assert_eq!(store.model().0, 3);
+
+        assert_eq!(store.can_undo(), true);
+        store.undo();
+        assert_eq!(store.model().0, 0);
+
+        assert_eq!(store.can_undo(), false);
+    }
+
+    #[test]
+    fn can_undo_redo_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        assert_eq!(store.can_undo(), false);
+        store.add(3);
+
+        assert_eq!(store.can_undo(), true);
+        assert_eq!(store.can_redo(), false);
+        store.undo();
+        assert_eq!(store.model().0, 0);
+
+        assert_eq!(store.can_undo(), false);
+        assert_eq!(store.can_redo(), true);
+        store.redo();
+        assert_eq!(store.model().0, 3);
+
+        assert_eq!(store.can_undo(), true);
+        assert_eq!(store.can_redo(), false);
+        store.undo();
+        assert_eq!(store.model().0, 0);
+    }
+
+    #[test]
+    fn undo_and_add_cmd() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+
+        store.add(3);
+        store.add(4);
+        store.add(5);
+
+        // 3, 4, 5
+        assert_eq!(store.model().0, 12);
+        store.undo(); // Undo0 Cancel Add(5)
+        // 3, 4
+        assert_eq!(store.model().0, 7);
+
+        store.add(6);
+        // 3, 4, 6
+        assert_eq!(store.model().0, 13);
+
+        store.undo(); // Cancel Add(6)
+        // 3, 4
+        assert_eq!(store.model().0, 7);
+
+        store.undo(); // Cancel Undo0
+        // 3
+        assert_eq!(store.model().0, 3);
+    }
+
+    #[test]
+    fn can_switch_branches_in_memory_store() {
+        use super::BranchInfo;
+
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(10).with_undo_tree();
+        store.add(1);
+        store.add(2);
+        store.add(4);
+        store.undo();
+        store.undo();
+        store.add(10);
+        store.undo();
+        store.add(20);
+        // 1 -+- 2 - 4    (branch 1)
+        //    +- 10       (branch 2)
+        //    +- 20
+        assert_eq!(store.model().0, 21);
+        assert_eq!(store.branches(), vec![
+            BranchInfo { id: 1, parent_id: None, fork: 1, len: 2 },
+            BranchInfo { id: 2, parent_id: None, fork: 1, len: 1 },
+        ]);
+
+        store.switch_branch(1).unwrap();
+        assert_eq!(store.model().0, 7);
+        assert!(!store.can_redo());
+        store.undo();
+        store.add(100);
+        // 1 -+- 2 -+- 100
+        //    |     +- 4  (branch 4)
+        //    +- 10       (branch 2)
+        //    +- 20       (branch 3)
+        assert_eq!(store.model().0, 103);
+
+        store.switch_branch(3).unwrap();
+        assert_eq!(store.model().0, 21);
+        // The history 2 - 100 becomes branch 5 and branch 4 moves under it.
+        assert_eq!(store.branches(), vec![
+            BranchInfo { id: 2, parent_id: None, fork: 1, len: 1 },
+            BranchInfo { id: 4, parent_id: Some(5), fork: 1, len: 1 },
+            BranchInfo { id: 5, parent_id: None, fork: 1, len: 2 },
+        ]);
+
+        store.switch_branch(4).unwrap();
+        assert_eq!(store.model().0, 7);
+        store.undo();
+        store.undo();
+        store.undo();
+        assert_eq!(store.model().0, 0);
+        assert!(store.switch_branch(4).is_err());
+    }
+
+    #[test]
+    fn clean_point_moves_with_branch_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(10).with_undo_tree();
+        store.add(1);
+        store.add(2);
+        store.mark_clean();
+        store.add(4);
+        store.undo();
+        store.undo();
+        store.add(10);
+        // 1 -+- 2 (clean) - 4 (branch 1)
+        //    +- 10
+        assert!(store.is_dirty());
+
+        store.switch_branch(1).unwrap();
+        assert!(store.is_dirty());
+        store.undo();
+        assert_eq!(store.model().0, 3);
+        assert!(!store.is_dirty());
+
+        // A new clean point drops the one kept in the branch.
+        store.undo();
+        store.add(20);
+        store.mark_clean();
+        store.switch_branch(3).unwrap();
+        store.undo();
+        assert_eq!(store.model().0, 3);
+        assert!(store.is_dirty());
+    }
+
+    #[test]
+    fn failed_switch_leaves_in_memory_store_unchanged() {
+        use super::BranchInfo;
+
+        let mut store: InMemoryUndoStore<CheckedAdd, Sum, ()> = InMemoryUndoStore::new(10).with_undo_tree();
+        store.add_cmd(CheckedAdd(1));
+        store.add_cmd(CheckedAdd(2));
+        store.add_cmd(CheckedAdd(-3));
+        store.undo();
+        store.undo();
+        store.add_cmd(CheckedAdd(10));
+        store.undo();
+        store.add_cmd(CheckedAdd(20));
+        // 1 -+- 2 - -3   (branch 1)
+        //    +- 10       (branch 2)
+        //    +- 20
+        let branches = vec![
+            BranchInfo { id: 1, parent_id: None, fork: 1, len: 2 },
+            BranchInfo { id: 2, parent_id: None, fork: 1, len: 1 },
+        ];
+        assert_eq!(store.branches(), branches);
+
+        // Redoing -3 fails since the sum becomes negative.
+        store.irreversible_mutate(Box::new(|model| model.0 -= 1));
+        let err = store.switch_branch(1).unwrap_err();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::CmdFailed { seq_no: 3, .. }), "{:?}", err);
+        assert_eq!(store.model().0, 20);
+        assert_eq!(store.seq_no(), 2);
+        assert!(!store.can_redo());
+        assert_eq!(store.branches(), branches);
+
+        store.irreversible_mutate(Box::new(|model| model.0 += 1));
+        store.switch_branch(1).unwrap();
+        assert_eq!(store.model().0, 0);
+        assert_eq!(store.seq_no(), 3);
+    }
+
+    #[test]
+    fn can_go_to_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        store.add(1);
+        store.add(2);
+        store.add(3);
+        store.add(4); // Add(1) is removed.
+        assert_eq!(store.seq_no(), 4);
+        assert_eq!(store.seq_no_range(), 1..=4);
+
+        store.go_to(1);
+        assert_eq!(store.model().0, 1);
+        assert!(!store.can_undo());
+        store.go_to(3);
+        assert_eq!(store.model().0, 6);
+        store.redo();
+        assert_eq!(store.model().0, 10);
+
+        let err = store.try_go_to(0).err().unwrap();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::SeqNoOutOfRange(0)), "{:?}", err);
+        assert_eq!(store.model().0, 10);
+    }
+
+    #[test]
+    fn can_list_history_in_memory_store() {
+        use super::HistoryEntry;
+
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        assert_eq!(store.history().count(), 0);
+        store.add(1);
+        store.add(2);
+        store.sub(3);
+        store.add(4); // Add(1) is removed.
+        store.undo();
+        assert_eq!(store.history().collect::<Vec<_>>(), vec![
+            HistoryEntry { seq_no: 2, undoable: true, grouped: false, cmd: &SumCmd::Add(2) },
+            HistoryEntry { seq_no: 3, undoable: true, grouped: false, cmd: &SumCmd::Sub(3) },
+            HistoryEntry { seq_no: 4, undoable: false, grouped: false, cmd: &SumCmd::Add(4) },
+        ]);
+    }
+
+    #[test]
+    fn can_group_cmds_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(10).with_merge_timeout(Duration::from_secs(60));
+        store.add(1);
+        store.group(|store| {
+            store.add(2); // Not merged across the group boundary.
+            store.begin_group();
+            store.sub(3);
+            store.end_group();
+            store.add(4);
+        });
+        store.add(5); // Not merged into the group.
+        assert_eq!(store.model().0, 9);
+
+        store.undo();
+        assert_eq!(store.model().0, 4);
+        store.undo();
+        assert_eq!(store.model().0, 1);
+        assert_eq!(store.history().map(|e| e.grouped).collect::<Vec<_>>(), vec![false, false, true, true, false]);
+
+        store.redo();
+        assert_eq!(store.model().0, 4);
+        store.go_to(3);
+        assert_eq!(store.model().0, 0);
+        store.undo();
+        assert_eq!(store.model().0, 1);
+    }
+
+    #[test]
+    fn can_describe_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        assert_eq!(store.undo_description(), None);
+        store.add(1);
+        store.sub(2);
+        assert_eq!(store.undo_description(), None);
+        store.undo();
+        assert_eq!(store.undo_description(), Some("Add 1".to_owned()));
+        assert_eq!(store.redo_description(), None);
+        store.undo();
+        assert_eq!(store.undo_description(), None);
+        assert_eq!(store.redo_description(), Some("Add 1".to_owned()));
+    }
+
+    #[test]
+    fn group_is_described_by_first_described_cmd_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(10);
+        store.add(1);
+        store.group(|store| {
+            store.sub(2);
+            store.add(3);
+            store.add(4);
+        });
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        store.undo();
+        assert_eq!(store.undo_description(), Some("Add 1".to_owned()));
+        assert_eq!(store.redo_description(), Some("Add 3".to_owned()));
+        store.undo();
+        assert_eq!(store.redo_description(), Some("Add 1".to_owned()));
+    }
+
+    #[test]
+    fn try_undo_redo_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        store.try_add_cmd(SumCmd::Add(3)).unwrap();
+        assert_eq!(store.try_mutate(Box::new(|_| Err(()))).unwrap(), Err(()));
+        store.try_undo().unwrap();
+        assert_eq!(store.model().0, 0);
+        store.try_undo().unwrap(); // Just ignored.
+        store.try_redo().unwrap();
+        assert_eq!(store.model().0, 3);
+    }
+
+    #[test]
+    fn failed_transactional_mutation_is_rolled_back_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        store.add(1);
+        assert_eq!(store.mutate_transactionally(Box::new(|model| { model.0 += 10; Err(()) })), Err(()));
+        assert_eq!(store.model().0, 1);
+        assert!(!store.can_redo());
+
+        store.mutate_transactionally(Box::new(|model| { model.0 += 2; Ok(SumCmd::Add(2)) })).unwrap();
+        assert_eq!(store.model().0, 3);
+        store.undo();
+        assert_eq!(store.model().0, 1);
+    }
+
+    // Adds the value. Fails if the sum becomes negative.
+    struct CheckedAdd(i32);
+
+    impl Cmd for CheckedAdd {
+        type Model = Sum;
+
+        fn redo(&self, model: &mut Self::Model) {
+            self.try_redo(model).unwrap();
+        }
+
+        fn undo(&self, model: &mut Self::Model) {
+            self.try_undo(model).unwrap();
+        }
+
+        fn try_redo(&self, model: &mut Self::Model) -> Result<(), crate::cmd::CmdError> {
+            if model.0 + self.0 < 0 {
+                return Err("Negative sum.".into());
+            }
+            model.0 += self.0;
+            Ok(())
+        }
+
+        fn try_undo(&self, model: &mut Self::Model) -> Result<(), crate::cmd::CmdError> {
+            CheckedAdd(-self.0).try_redo(model)
+        }
+    }
+
+    #[test]
+    fn failed_cmd_leaves_in_memory_store_unchanged() {
+        let mut store: InMemoryUndoStore<CheckedAdd, Sum, ()> = InMemoryUndoStore::new(10);
+        store.add_cmd(CheckedAdd(1));
+        store.group(|store| {
+            store.add_cmd(CheckedAdd(2));
+            store.add_cmd(CheckedAdd(3));
+        });
+        store.irreversible_mutate(Box::new(|model| model.0 = 4));
+
+        // Undoing Add(3) succeeds but Add(2) fails.
+        let err = store.try_undo().unwrap_err();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::CmdFailed { seq_no: 2, .. }));
+        assert_eq!(store.seq_no(), 3);
+        assert_eq!(store.model().0, 4);
+
+        let err = store.try_go_to(0).unwrap_err();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::CmdFailed { seq_no: 2, .. }));
+        assert_eq!(store.seq_no(), 3);
+        assert_eq!(store.model().0, 4);
+
+        let err = store.try_add_cmd(CheckedAdd(-5)).unwrap_err();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::CmdFailed { seq_no: 4, .. }));
+        assert_eq!(store.seq_no(), 3);
+        assert!(!store.can_redo());
+
+        store.add_cmd(CheckedAdd(-4));
+        store.undo();
+        assert_eq!(store.model().0, 4);
+    }
+
+    #[test]
+    fn can_track_dirty_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        assert!(!store.is_dirty());
+        store.add(1);
+        assert!(store.is_dirty());
+        store.mark_clean();
+        store.add(2);
+        assert!(store.is_dirty());
+        store.undo();
+        assert!(!store.is_dirty());
+        store.add(3); // The clean point is kept.
+        store.undo();
+        assert!(!store.is_dirty());
+
+        store.undo();
+        store.add(4); // The clean point is discarded with the redo history.
+        store.undo();
+        store.redo();
+        assert!(store.is_dirty());
+
+        store.undo();
+        store.mark_clean();
+        store.redo();
+        store.add(5);
+        store.add(6); // The clean point is removed due to the capacity.
+        store.go_to(1);
+        assert!(store.is_dirty());
+
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3).with_merge_timeout(Duration::from_secs(60));
+        store.add(1);
+        store.mark_clean();
+        store.add(2); // Merged into the clean command.
+        assert!(store.is_dirty());
+    }
+
+    #[test]
+    fn capacity_is_exact_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        for i in 1..=5 {
+            store.add(i);
+        }
+        assert_eq!(store.seq_no_range(), 2..=5);
+        assert_eq!(store.history().count(), 3);
+        while store.can_undo() {
+            store.undo();
+        }
+        assert_eq!(store.model().0, 3);
+
+        // At least one command is kept.
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(0);
+        store.add(1);
+        store.add(2);
+        assert_eq!(store.seq_no_range(), 1..=2);
+    }
+
+    // Counts how many times commands are undone/redone.
+    struct CountedAdd(i32, std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl Cmd for CountedAdd {
+        type Model = Sum;
+
+        fn redo(&self, model: &mut Self::Model) {
+            model.0 += self.0;
+            self.1.set(self.1.get() + 1);
+        }
+
+        fn undo(&self, model: &mut Self::Model) {
+            model.0 -= self.0;
+            self.1.set(self.1.get() + 1);
+        }
+    }
+
+    #[test]
+    fn can_restore_snapshot_in_memory_store() {
+        use std::{cell::{Cell, RefCell}, rc::Rc};
+        use super::StoreEvent;
+
+        let mut store: InMemoryUndoStore<CountedAdd, Sum, ()> = InMemoryUndoStore::new(12).with_snapshot_interval(10);
+        let events = Rc::new(RefCell::new(vec![]));
+        let events_ = events.clone();
+        store.subscribe(Box::new(move |event| if let StoreEvent::SnapshotRestored { seq_no } = event { events_.borrow_mut().push(*seq_no) }));
+        let steps = Rc::new(Cell::new(0));
+        for _ in 0..20 {
+            store.add_cmd(CountedAdd(1, steps.clone()));
+        }
+
+        steps.set(0);
+        store.restore_to(9).unwrap(); // Restored from the snapshot at 10.
+        assert_eq!(store.model().0, 9);
+        assert_eq!(steps.get(), 1);
+        store.restore_to(8).unwrap(); // Restored even if the current position is nearer.
+        assert_eq!(store.model().0, 8);
+        assert_eq!(steps.get(), 3);
+        assert_eq!(*events.borrow(), vec![10, 10]);
+
+        // go_to() never restores snapshots.
+        steps.set(0);
+        store.go_to(20);
+        assert_eq!(steps.get(), 12);
+        for _ in 0..5 {
+            store.add_cmd(CountedAdd(1, steps.clone()));
+        }
+        steps.set(0);
+        store.restore_to(13).unwrap();
+        assert_eq!(store.model().0, 13);
+        assert_eq!(steps.get(), 7);
+        assert_eq!(*events.borrow(), vec![10, 10, 20]);
+
+        // Snapshots after the fork are discarded with the redo history.
+        store.add_cmd(CountedAdd(100, steps.clone()));
+        assert_eq!(store.snapshots.as_ref().unwrap().models.len(), 0);
+    }
+
+    #[test]
+    fn can_go_back_to_base_snapshot_in_memory_store() {
+        use std::{cell::{Cell, RefCell}, rc::Rc};
+        use super::StoreEvent;
+
+        let mut store: InMemoryUndoStore<CountedAdd, Sum, ()> = InMemoryUndoStore::new(2).with_snapshot_interval(10);
+        let events = Rc::new(RefCell::new(vec![]));
+        let events_ = events.clone();
+        store.subscribe(Box::new(move |event| if let StoreEvent::SnapshotRestored { seq_no } = event { events_.borrow_mut().push(*seq_no) }));
+        let steps = Rc::new(Cell::new(0));
+        for _ in 0..14 {
+            store.add_cmd(CountedAdd(1, steps.clone()));
+        }
+        // The snapshot at 10 is kept as the base after the commands until 12 are removed.
+        assert_eq!(store.seq_no_range(), 10..=14);
+        store.go_to(13);
+        store.mark_clean();
+        store.go_to(12);
+        assert!(store.can_undo());
+        store.undo(); // Restored from the base.
+        assert_eq!(store.seq_no(), 10);
+        assert_eq!(store.model().0, 10);
+        assert!(!store.can_undo());
+        assert!(store.can_redo());
+        assert_eq!(*events.borrow(), vec![10]);
+        // The states between the base and the oldest command cannot be reached.
+        let err = store.try_go_to(11).err().unwrap();
+        assert!(matches!(err.current_context(), super::InMemoryStoreErr::SeqNoOutOfRange(11)), "{:?}", err);
+
+        store.go_to(13);
+        assert_eq!(store.model().0, 13);
+        assert!(!store.is_dirty());
+        assert_eq!(*events.borrow(), vec![10, 12]);
+        store.go_to(10);
+        store.redo(); // Back to the oldest command.
+        assert_eq!(store.seq_no(), 12);
+        assert_eq!(store.model().0, 12);
+
+        // A command added at the base discards the history.
+        store.go_to(10);
+        store.add_cmd(CountedAdd(100, steps.clone()));
+        assert_eq!(store.seq_no_range(), 10..=11);
+        assert_eq!(store.model().0, 110);
+        assert!(store.is_dirty());
+        store.undo();
+        assert_eq!(store.model().0, 10);
+        assert!(!store.can_undo());
+    }
+
+    // Holds a text of the length.
+    struct PasteCmd(usize);
+
+    impl Cmd for PasteCmd {
+        type Model = Sum;
+
+        fn redo(&self, model: &mut Self::Model) {
+            model.0 += self.0 as i32;
+        }
+
+        fn undo(&self, model: &mut Self::Model) {
+            model.0 -= self.0 as i32;
+        }
+
+        fn merge(&self, other: &Self) -> Option<Self> {
+            Some(PasteCmd(self.0 + other.0))
+        }
+
+        fn approx_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn can_limit_memory_in_memory_store() {
+        let mut store: InMemoryUndoStore<PasteCmd, Sum, ()> = InMemoryUndoStore::new(100).with_memory_budget(1000);
+        store.add_cmd(PasteCmd(300));
+        store.add_cmd(PasteCmd(300));
+        store.add_cmd(PasteCmd(300));
+        assert_eq!(store.retained_size(), 900);
+        store.add_cmd(PasteCmd(200)); // The oldest one is removed.
+        assert_eq!(store.retained_size(), 800);
+        assert_eq!(store.seq_no_range(), 1..=4);
+
+        store.undo();
+        store.add_cmd(PasteCmd(2000)); // Exceeds the budget alone but kept so that it can be undone.
+        assert_eq!(store.retained_size(), 2000);
+        assert_eq!(store.seq_no_range(), 3..=4);
+        store.undo();
+        assert_eq!(store.model().0, 900);
+        assert!(!store.can_undo());
+    }
+
+    #[test]
+    fn branches_are_not_counted_in_memory_budget_in_memory_store() {
+        let mut store: InMemoryUndoStore<PasteCmd, Sum, ()> = InMemoryUndoStore::new(100)
+            .with_memory_budget(1000).with_undo_tree();
+        store.add_cmd(PasteCmd(300));
+        store.add_cmd(PasteCmd(600));
+        store.undo();
+        store.add_cmd(PasteCmd(800)); // 600 becomes branch 1 and the oldest one is removed.
+        assert_eq!(store.retained_size(), 800);
+        assert_eq!(store.seq_no_range(), 1..=2);
+        assert_eq!(store.branches().len(), 1);
+
+        store.switch_branch(1).unwrap(); // 800 becomes branch 2.
+        assert_eq!(store.retained_size(), 600);
+        assert_eq!(store.seq_no_range(), 1..=2);
+        assert_eq!(store.model().0, 900);
+
+        store.add_cmd(PasteCmd(500)); // The oldest one is removed, which drops branch 2 forking before it.
+        assert_eq!(store.retained_size(), 500);
+        assert_eq!(store.seq_no_range(), 2..=3);
+        assert!(store.branches().is_empty());
+    }
+
+    #[test]
+    fn merged_cmd_is_limited_by_memory_budget_in_memory_store() {
+        let mut store: InMemoryUndoStore<PasteCmd, Sum, ()> = InMemoryUndoStore::new(100)
+            .with_memory_budget(1000).with_merge_timeout(Duration::from_secs(60));
+        store.add_cmd(PasteCmd(300));
+        store.undo();
+        store.redo(); // Not merged after redo.
+        store.add_cmd(PasteCmd(300));
+        assert_eq!(store.seq_no_range(), 0..=2);
+        store.add_cmd(PasteCmd(200)); // Merged into the last command.
+        assert_eq!(store.retained_size(), 800);
+        store.add_cmd(PasteCmd(400)); // Merged and exceeds the budget. The oldest one is removed.
+        assert_eq!(store.retained_size(), 900);
+        assert_eq!(store.seq_no_range(), 1..=2);
+        assert_eq!(store.model().0, 1200);
+        store.undo();
+        assert_eq!(store.model().0, 300);
+        assert!(!store.can_undo());
+    }
+
+    #[test]
+    fn can_observe_in_memory_store() {
+        use std::{cell::RefCell, rc::Rc};
+        use super::StoreEvent;
+
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3).with_merge_timeout(Duration::from_secs(60));
+        let events = Rc::new(RefCell::new(vec![]));
+        let events_ = events.clone();
+        let id = store.subscribe(Box::new(move |event| events_.borrow_mut().push(event.clone())));
+        store.add(1);
+        store.add(2); // Merged into Add(3)
+        store.group(|store| {
+            store.sub(3);
+            store.add(4);
+        });
+        store.undo();
+        store.redo();
+        store.go_to(1);
+        store.sub(5);
+        store.sub(6); // Merged into Sub(11)
+        assert_eq!(*events.borrow(), vec![
+            StoreEvent::CmdAdded { seq_no: 1, merged: false },
+            StoreEvent::CmdAdded { seq_no: 1, merged: true },
+            StoreEvent::CmdAdded { seq_no: 2, merged: false },
+            StoreEvent::CmdAdded { seq_no: 3, merged: false },
+            StoreEvent::Undone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 2 },
+            StoreEvent::Redone { seq_no: 2 },
+            StoreEvent::Redone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 2 },
+            StoreEvent::HistoryTruncated { seq_nos: 2..=3 },
+            StoreEvent::CmdAdded { seq_no: 2, merged: false },
+            StoreEvent::CmdAdded { seq_no: 2, merged: true },
+        ]);
+
+        events.borrow_mut().clear();
+        store.undo();
+        store.add(7);
+        store.sub(8);
+        store.add(9); // Add(3) is removed due to the capacity.
+        assert_eq!(*events.borrow(), vec![
+            StoreEvent::Undone { seq_no: 2 },
+            StoreEvent::HistoryTruncated { seq_nos: 2..=2 },
+            StoreEvent::CmdAdded { seq_no: 2, merged: false },
+            StoreEvent::CmdAdded { seq_no: 3, merged: false },
+            StoreEvent::HistoryTruncated { seq_nos: 1..=1 },
+            StoreEvent::CmdAdded { seq_no: 4, merged: false },
+        ]);
+
+        assert!(store.unsubscribe(id));
+        assert!(!store.unsubscribe(id));
+        events.borrow_mut().clear();
+        store.undo();
+        assert!(events.borrow().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_save_and_load_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        store.add(1);
+        store.sub(2);
+        store.group(|store| {
+            store.add(3);
+            store.sub(4);
+        });
+        // Add(1) is removed due to the capacity. Sub(2), Add(3), Sub(4)
+        store.mark_clean();
+        store.undo();
+        assert_eq!(store.model().0, -1);
+
+        let mut file = vec![];
+        store.save_to(&mut file).unwrap();
+
+        let mut loaded: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+        loaded.load_from(file.as_slice()).unwrap();
+        assert_eq!(loaded.model().0, -1);
+        assert_eq!(loaded.seq_no_range(), 1..=4);
+        assert_eq!(loaded.seq_no(), 2);
+        assert!(loaded.is_dirty());
+
+        loaded.redo();
+        assert_eq!(loaded.model().0, -2);
+        assert!(!loaded.is_dirty());
+        loaded.undo();
+        loaded.undo();
+        assert_eq!(loaded.model().0, 1);
+        assert!(!loaded.can_undo());
+
+        // The oldest command is removed if the capacity is smaller.
+        let mut small: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(2);
+        small.load_from(file.as_slice()).unwrap();
+        assert_eq!(small.seq_no_range(), 2..=4);
+        assert_eq!(small.seq_no(), 2);
+        assert!(!small.can_undo());
+        small.redo();
+        assert_eq!(small.model().0, -2);
+
+        assert!(loaded.load_from(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn cmds_are_not_merged_without_timeout() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
+
+        store.add(1);
+        store.add(2);
+        assert_eq!(store.model().0, 3);
+
+        store.undo();
+        assert_eq!(store.model().0, 1);
+    }
+
+    #[test]
+    fn can_merge_cmds_in_memory_store() {
+        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3).with_merge_timeout(Duration::from_secs(60));
+
+        store.add(1);
+        store.add(2); // Merged into Add(3)
+        store.sub(3); // Cannot merge Sub into Add.
+        store.sub(4); // Merged into Sub(7)
+        // Add(3), Sub(7)
+        assert_eq!(store.model().0, -4);
+
+        store.undo();
+        assert_eq!(store.model().0, 3);
+
+        // Not merged after undo.
+        store.sub(5);
+        // Add(3), Sub(5)
+        assert_eq!(store.model().0, -2);
+
+        store.undo();
+        assert_eq!(store.model().0, 3);
+        store.undo();
+        assert_eq!(store.model().0, 0);
+        assert!(!store.can_undo());
+    }
+}
+
+#[cfg(feature = "persistence")]
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::derivable_impls)]
+mod persistent_tests {
+    use std::{thread, time::Duration};
+
+    use tracing::level_filters::LevelFilter;
+    use crate::undo_store::{self, SQLITE_FILE_NAME};
+    use super::{Cmd, PersisterServer, SqliteUndoStore, UndoStore};
+
+    #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    enum Trace {
+        Add, Sub
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Clone)]
+    struct SerSum {
+        pub value: i32,
+        pub trace: Vec<Trace>,
+        #[serde(skip)]
+        pub trace_count: usize,
+    }
+
+    impl SerSum {
+        fn value(&self) -> i32 {
+            self.value
+        }
+
+        fn trace_count(&self) -> usize {
+            self.trace_count
+        }
+
+        fn add(&mut self, i: i32) {
+            let new_val = self.value + i;
+            self.value = new_val;
+            self.trace.push(Trace::Add);
+            self.trace_count += 1;
+        }
+
+        fn sub(&mut self, i: i32) {
+            let new_val = self.value - i;
+            self.value = new_val;
+            self.trace.push(Trace::Sub);
+            self.trace_count += 1;
+        }
+    }
+
+    impl Default for SerSum {
+        fn default() -> Self {
+            Self { value: 0, trace: vec![], trace_count: 0 }
+        }
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    enum SerSumCmd {
+        Add(i32), Sub(i32),
+    }
+
+    impl Cmd for SerSumCmd {
+        type Model = SerSum;
+
+        fn redo(&self, model: &mut Self::Model) {
+            match self {
+                SerSumCmd::Add(i) => model.add(*i),
+                SerSumCmd::Sub(i) => model.sub(*i),
+            }
+        }
+
+        fn undo(&self, model: &mut Self::Model) {
+            match self {
+                SerSumCmd::Add(i) => model.value -= *i,
+                SerSumCmd::Sub(i) => model.value += *i,
+            }
+        }
+
+        fn merge(&self, other: &Self) -> Option<Self> {
+            match (self, other) {
+                (SerSumCmd::Add(i), SerSumCmd::Add(j)) => Some(SerSumCmd::Add(i + j)),
+                (SerSumCmd::Sub(i), SerSumCmd::Sub(j)) => Some(SerSumCmd::Sub(i + j)),
+                _ => None,
+            }
+        }
+
+        // Leave Sub undescribed to test commands without descriptions.
+        fn describe(&self) -> Option<String> {
+            match self {
+                SerSumCmd::Add(i) => Some(format!("Add {}", i)),
+                SerSumCmd::Sub(_) => None,
+            }
+        }
+    }
+
+    impl crate::cmd::SerializableCmd for SerSumCmd {
+    }
+
+    trait SerModel {
+        fn add(&mut self, to_add: i32) -> Result<(), super::SqliteUndoStoreError>;
+        fn sub(&mut self, to_sub: i32) -> Result<(), super::SqliteUndoStoreError>;
+    }
+
+    impl SerModel for super::SqliteUndoStore::<SerSumCmd, SerSum, ()> {
+        fn add(&mut self, to_add: i32) -> Result<(), super::SqliteUndoStoreError> {
+            self.add_cmd(SerSumCmd::Add(to_add));
+            Ok(())
+        }
+
+        fn sub(&mut self, to_sub: i32) -> Result<(), super::SqliteUndoStoreError> {
+            self.add_cmd(SerSumCmd::Sub(to_sub));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn file_store_can_lock() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let store = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+
+        let store2_err = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).err().unwrap();
+        match store2_err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
+            super::SqliteUndoStoreError::CannotLock { path: err_dir, error: _ } => {
+                let lock_file_path = crate::undo_store::PersisterServer::<SerSumCmd, SerSum, ()>::lock_file_path(&dir);
+                assert_eq!(err_dir.as_path(), lock_file_path);
+            },
+            _ => {panic!("Test failed. {:?}", store2_err)},
+        };
+
+        drop(store);
+
+        let _ = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+    }
+
+    #[test]
+    fn stale_lock_file_is_ignored() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        drop(store);
+
+        // Owner file left by a crashed process.
+        let owner_file_path = PersisterServer::<SerSumCmd, SerSum, ()>::lock_owner_file_path(&dir);
+        std::fs::write(&owner_file_path, "pid=1\n").unwrap();
+
+        assert_eq!(SqliteUndoStore::<SerSumCmd, SerSum, ()>::lock_owner(&dir).unwrap(), None);
+        SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+    }
+
+    #[test]
+    fn can_inspect_lock_owner_and_force_unlock() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+
+        let owner = SqliteUndoStore::<SerSumCmd, SerSum, ()>::lock_owner(&dir).unwrap().unwrap();
+        assert_eq!(owner.pid, Some(std::process::id()));
+        assert!(owner.locked_at.is_some());
+
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::force_unlock(&dir).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::CannotLock { .. }));
+
+        drop(store);
+        let lock_file_path = PersisterServer::<SerSumCmd, SerSum, ()>::lock_file_path(&dir);
+        let owner_file_path = PersisterServer::<SerSumCmd, SerSum, ()>::lock_owner_file_path(&dir);
+        assert!(lock_file_path.exists());
+        assert!(!owner_file_path.exists());
+        assert_eq!(SqliteUndoStore::<SerSumCmd, SerSum, ()>::lock_owner(&dir).unwrap(), None);
+
+        // Owner file left by a crashed process.
+        std::fs::write(&owner_file_path, "pid=1\n").unwrap();
+        SqliteUndoStore::<SerSumCmd, SerSum, ()>::force_unlock(&dir).unwrap();
+        assert!(lock_file_path.exists());
+        assert!(!owner_file_path.exists());
+    }
+
+    #[test]
+    fn can_open_read_only() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open_read_only(dir.clone(), undo_store::Options::new()).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::NotFound(_)));
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        store.add(2).unwrap();
+        store.add(3).unwrap();
+        wait_add_cmd_completion(&mut store);
+
+        // Can open while the store is locked.
+        let mut ro_store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open_read_only(dir.clone(), undo_store::Options::new()).unwrap();
+        assert!(ro_store.is_read_only());
+        assert_eq!(ro_store.model().value(), 6);
+        ro_store.try_undo().unwrap();
+        ro_store.try_undo().unwrap();
+        assert_eq!(ro_store.model().value(), 1);
+        ro_store.try_redo().unwrap();
+        assert_eq!(ro_store.model().value(), 3);
+
+        let err = ro_store.try_add_cmd(SerSumCmd::Add(100)).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::ReadOnly(_)));
+        assert_eq!(ro_store.model().value(), 3);
+        drop(ro_store);
+
+        // Cursor is not written back.
+        drop(store);
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.model().value(), 6);
+    }
+
+    #[test]
+    fn newer_schema_version_is_rejected() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        drop(store);
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("update version set version = ?1", [undo_store::SCHEMA_VERSION + 1]).unwrap();
+        }
+
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).err().unwrap();
+        match err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
+            super::SqliteUndoStoreError::UnsupportedSchemaVersion { path: _, version, supported } => {
+                assert_eq!(*version, undo_store::SCHEMA_VERSION + 1);
+                assert_eq!(*supported, undo_store::SCHEMA_VERSION);
+            }
+            _ => panic!("Test failed. {:?}", err),
+        }
+
+        // Lock should be released.
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("update version set version = ?1", [undo_store::SCHEMA_VERSION]).unwrap();
+        }
+        SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+    }
+
+    #[test]
+    fn migrations_are_applied_in_order() {
+        use tempfile::tempdir;
+
+        fn to_v2(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+            tx.execute_batch("create table migrated(name text not null); insert into migrated (name) values ('v2');")
+        }
+        fn to_v3(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+            tx.execute_batch("insert into migrated (name) values ('v3');")
+        }
+
+        let dir = tempdir().unwrap();
+        let sqlite_path = dir.as_ref().join(SQLITE_FILE_NAME);
+        let mut conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+        PersisterServer::<SerSumCmd, SerSum, ()>::create_tables(&conn).unwrap();
+
+        PersisterServer::<SerSumCmd, SerSum, ()>::migrate(&sqlite_path, &mut conn, &[to_v2]).unwrap();
+        assert_eq!(PersisterServer::<SerSumCmd, SerSum, ()>::get_schema_version(&conn).unwrap(), 2);
+
+        PersisterServer::<SerSumCmd, SerSum, ()>::migrate(&sqlite_path, &mut conn, &[to_v2, to_v3]).unwrap();
+        assert_eq!(PersisterServer::<SerSumCmd, SerSum, ()>::get_schema_version(&conn).unwrap(), 3);
+
+        let mut stmt = conn.prepare("select name from migrated order by rowid").unwrap();
+        let names: Vec<String> = stmt.query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(names, ["v2", "v3"]);
+    }
+
+    #[test]
+    fn can_store_with_json_codec() {
+        use std::sync::Arc;
+        use tempfile::tempdir;
+        use crate::codec::{Codec, JsonCodec};
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(
+            dir.clone(), undo_store::Options::new().with_codec(Arc::new(JsonCodec))
+        ).unwrap();
+        store.add(123).unwrap();
+        store.sub(23).unwrap();
+        store.wait_until_saved().unwrap();
+        drop(store);
 
-#[cfg(feature = "persistence")]
-struct Db<'a> {
-    sqlite_path: PathBuf,
-    conn: &'a Connection,
-}
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            let codec: String = conn.query_row("select value from metadata where key = 'codec'", [], |row| row.get(0)).unwrap();
+            assert_eq!(codec, "json");
+            let serialized: Vec<u8> = conn.query_row("select serialized from command where command_id = 1", [], |row| row.get(0)).unwrap();
+            assert_eq!(std::str::from_utf8(&serialized).unwrap(), r#"{"Add":123}"#);
+        }
 
-#[cfg(feature = "persistence")]
-impl<'a> Db<'a> {
-    fn new(sqlite_path: PathBuf, conn: &'a Connection) -> Self {
-        Self { sqlite_path, conn }
+        // The recorded codec is used even if another one is specified.
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.codec().name(), JsonCodec.name());
+        assert_eq!(store.model().value(), 100);
+        store.undo();
+        assert_eq!(store.model().value(), 123);
     }
 
-    #[inline]
-    fn exec<F, T>(&self, f: F) -> Result<T, Report<SqliteUndoStoreError>> where F: FnOnce(&Connection) -> std::result::Result<T, rusqlite::Error> {
-        let sqlite_path = self.sqlite_path.clone();
-        f(self.conn).map_err(|e| {
-            SqliteUndoStoreError::DbError(sqlite_path, e.into_report()).into_report()
-        })
-    }
-}
+    #[test]
+    fn unknown_codec_is_rejected() {
+        use std::sync::Arc;
+        use tempfile::tempdir;
+        use crate::codec::{Codec, CodecError, JsonCodec};
 
-#[cfg(feature = "persistence")]
-pub struct SqliteUndoStore<C, M, E>
-  where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned
-{
-    phantom: std::marker::PhantomData<C>,
-    phantome: std::marker::PhantomData<E>,
-    model: M,
-    #[allow(dead_code)]
-    options: Options<M>,
-    persister_client: PersisterClient,
-    base_dir: std::path::PathBuf,
-}
+        struct CustomCodec;
 
-pub const SQLITE_FILE_NAME: &'static str = "db.sqlite";
-pub const DEFAULT_UNDO_LIMIT: usize = 100;
+        impl Codec for CustomCodec {
+            fn name(&self) -> &str { "custom" }
 
-pub struct Options<M> {
-    pub undo_limit: usize,
-    pub merge_timeout: Option<Duration>,
+            fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+                JsonCodec.serialize(value)
+            }
 
-    /// Called when a snapshot is restored. If you have states that are out of scope to manage undo/redo operations, you can restore them here.
-    pub on_snapshot_restored: Option<Box<dyn FnOnce(M) -> M>>,
-}
+            fn deserialize<'de>(
+                &self, bytes: &'de [u8], visitor: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>
+            ) -> Result<(), CodecError> {
+                JsonCodec.deserialize(bytes, visitor)
+            }
+        }
 
-impl<M> Options<M> {
-    pub fn new() -> Self {
-        Self {
-            undo_limit: DEFAULT_UNDO_LIMIT,
-            merge_timeout: None,
-            on_snapshot_restored: None,
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(
+            dir.clone(), undo_store::Options::new().with_codec(Arc::new(CustomCodec))
+        ).unwrap();
+        store.add(123).unwrap();
+        store.wait_until_saved().unwrap();
+        drop(store);
+
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).err().unwrap();
+        match err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
+            super::SqliteUndoStoreError::UnknownCodec { path: _, name } => assert_eq!(name, "custom"),
+            _ => panic!("Test failed. {:?}", err),
         }
+
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(
+            dir.clone(), undo_store::Options::new().with_codec(Arc::new(CustomCodec))
+        ).unwrap();
+        assert_eq!(store.model().value(), 123);
     }
 
-    pub fn with_undo_limit(self, l: usize) -> Self {
-        Self {
-            undo_limit: l,
-            ..self
+    #[test]
+    fn unsupported_compression_is_rejected() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(123).unwrap();
+        store.wait_until_saved().unwrap();
+        let stats = store.compression_stats().unwrap();
+        assert_eq!(stats.raw_size, stats.stored_size);
+        assert_eq!(stats.ratio(), 1.0);
+        drop(store);
+
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("update command set compression = 'unknown'", []).unwrap();
+        }
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).err().unwrap();
+        match err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
+            super::SqliteUndoStoreError::UnsupportedCompression { path: _, name } => assert_eq!(name, "unknown"),
+            _ => panic!("Test failed. {:?}", err),
         }
     }
 
-    pub fn with_merge_timeout(self, timeout: Duration) -> Self {
-        Self {
-            merge_timeout: Some(timeout),
-            ..self
+    #[cfg(feature = "compression")]
+    #[test]
+    fn can_compress() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(3)).unwrap();
+        for i in 0..10 {
+            store.add(i).unwrap();
         }
-    }
+        store.wait_until_saved().unwrap();
+        drop(store);
 
-    pub fn with_on_snapshot_restored(self, on_snapshot_restored: Box<dyn FnOnce(M) -> M>) -> Self {
-        Self {
-            on_snapshot_restored: Some(on_snapshot_restored),
-            ..self
+        // Uncompressed rows are still readable.
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(
+            dir.clone(), undo_store::Options::new().with_undo_limit(3).with_compression(9)
+        ).unwrap();
+        assert_eq!(store.model().value(), 45);
+        for i in 10..100 {
+            store.add(i).unwrap();
         }
-    }
-}
+        store.wait_until_saved().unwrap();
+        let stats = store.compression_stats().unwrap();
+        assert!(stats.stored_size < stats.raw_size);
+        assert!(stats.ratio() < 1.0);
+        drop(store);
 
-#[cfg(feature = "persistence")]
-impl<C, M, E> SqliteUndoStore<C, M, E> where C: crate::cmd::SerializableCmd<Model = M>, M: Default + serde::Serialize + serde::de::DeserializeOwned {
-    pub fn dir(&self) -> &std::path::PathBuf {
-        &self.base_dir
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            let compression: Option<String> = conn.query_row("select compression from snapshot", [], |row| row.get(0)).unwrap();
+            assert_eq!(compression.as_deref(), Some(crate::compression::ZLIB_COMPRESSION_NAME));
+        }
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(3)).unwrap();
+        assert_eq!(store.model().value(), 4950);
+        assert_eq!(store.model().trace.len(), 100);
+        store.undo();
+        assert_eq!(store.model().value(), 4950 - 99);
     }
 
-    // Open the specified directory or newly create it if that does not exist.
-    pub fn open<P: AsRef<Path>>(dir: P, mut options: Options<M>) -> Result<Self, Report<SqliteUndoStoreError>>
-        where C: crate::cmd::SerializableCmd<Model = M> + serde::de::DeserializeOwned
-    {
-        let (cmd_sender, cmd_receiver) = mpsc::channel();
-        let (resp_sender, resp_receiver) = mpsc::channel();
-        
-        let undo_limit = options.undo_limit;
-        let merge_timeout = options.merge_timeout;
-        thread::spawn(move || {
-            let persister_server: PersisterServer<C, M, E> = PersisterServer::new(
-                cmd_receiver, resp_sender, undo_limit, merge_timeout,
-            );
-            persister_server.start();
-        });
+    #[cfg(feature = "compression")]
+    #[test]
+    fn invalid_compression_level_is_rejected() {
+        use tempfile::tempdir;
 
-        let (persister_client, serialized_model) = PersisterClient::open(
-            resp_receiver, cmd_sender, dir.as_ref().to_path_buf(), options.undo_limit
-        )?;
-        let model: M = bincode::deserialize(&serialized_model).map_err(|e|
-            SqliteUndoStoreError::CannotDeserialize {
-                path: Some(dir.as_ref().to_path_buf()), seq_no: persister_client.last_seq_no, ser_err: e
-            }
-        )?;
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_compression(10)).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::InvalidCompressionLevel(10)));
+        assert!(!dir.exists());
+    }
 
-        let model = if let Some(f) = options.on_snapshot_restored.take() {
-            f(model)
-        } else { model };
+    struct XorCipher(u8);
 
-        let store = SqliteUndoStore {
-            base_dir: dir.as_ref().to_path_buf(), model,
-            phantom: std::marker::PhantomData, phantome: std::marker::PhantomData,
-            options, persister_client,
-        };
+    impl crate::cipher::Cipher for XorCipher {
+        fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, crate::cipher::CipherError> {
+            Ok(plain.iter().map(|b| b ^ self.0).collect())
+        }
 
-        Ok(store)
+        fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, crate::cipher::CipherError> {
+            Ok(encrypted.iter().map(|b| b ^ self.0).collect())
+        }
     }
 
-    pub fn save_as<P: AsRef<Path>>(&mut self, save_to: P) -> Result<(), Report<SqliteUndoStoreError>> {
-        let mut to = save_to.as_ref().to_path_buf();
-        to.push(SQLITE_FILE_NAME);
+    #[test]
+    fn can_encrypt() {
+        use std::sync::Arc;
+        use tempfile::tempdir;
+        use crate::codec::JsonCodec;
 
-        let mut from = self.base_dir.clone();
-        from.push(SQLITE_FILE_NAME);
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_codec(Arc::new(JsonCodec)).with_undo_limit(3);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options().with_cipher(Arc::new(XorCipher(1)))).unwrap();
+        store.set_metadata(Some(b"alice".to_vec()));
+        for i in 0..10 {
+            store.add(i).unwrap();
+        }
+        store.wait_until_saved().unwrap();
+        drop(store);
 
-        std::fs::copy(&from, &to).map_err(|error| Report::from(SqliteUndoStoreError::CannotCopyStore { from: from.clone(), to: to.clone(), error }))?;
-        Ok(())
-    }
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            let serialized: Vec<u8> = conn.query_row("select serialized from command where command_id = 10", [], |row| row.get(0)).unwrap();
+            assert_ne!(serialized, br#"{"Add":9}"#);
+            let decrypted: Vec<u8> = serialized.iter().map(|b| b ^ 1).collect();
+            assert_eq!(decrypted, br#"{"Add":9}"#);
+            let metadata: Vec<u8> = conn.query_row("select metadata from command where command_id = 10", [], |row| row.get(0)).unwrap();
+            assert_ne!(metadata, b"alice");
+        }
 
-    fn _add_cmd(&mut self, cmd: C) -> Result<(), Report<SqliteUndoStoreError>> {
-        let serialized: Vec<u8> = bincode::serialize(&cmd).map_err(
-            |e| SqliteUndoStoreError::SerializeError(e)
-        )?;
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options().with_cipher(Arc::new(XorCipher(1)))).unwrap();
+        assert_eq!(store.model().value(), 45);
+        assert_eq!(store.cmd_records().unwrap()[0].metadata, Some(b"alice".to_vec()));
+        store.undo();
+        assert_eq!(store.model().value(), 36);
+        drop(store);
 
-        self.persister_client.add_command(serialized);
-        Ok(())
-    }
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options().with_cipher(Arc::new(XorCipher(2)))).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::WrongKey(_)), "{:?}", err);
 
-    pub fn saved(&mut self) -> Result<bool, Report<SqliteUndoStoreError>> {
-        self.persister_client.process_resp()?;
-        Ok(self.persister_client.saved())
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::KeyRequired(_)), "{:?}", err);
     }
 
-    pub fn wait_until_saved(&mut self) {
-        loop {
-            if self.saved().unwrap() {
-                break;
+    #[test]
+    fn descriptions_are_encrypted() {
+        use std::sync::Arc;
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_cipher(Arc::new(XorCipher(1)));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        store.add(3).unwrap();
+        store.sub(1).unwrap();
+        store.add(4).unwrap();
+        store.wait_until_saved().unwrap();
+        assert_eq!(store.undo_description(), Some("Add 4".to_owned()));
+        drop(store);
+
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            let mut stmt = conn.prepare("select description from command order by command_id").unwrap();
+            let descriptions: Vec<rusqlite::types::Value> = stmt.query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+            assert_eq!(descriptions.len(), 3);
+            for description in descriptions {
+                assert!(!matches!(description, rusqlite::types::Value::Text(_)), "{:?}", description);
+                if let rusqlite::types::Value::Blob(data) = description {
+                    assert!(!String::from_utf8_lossy(&data).contains("Add"), "{:?}", data);
+                }
             }
-            thread::sleep(Duration::from_millis(100));
         }
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.undo_description(), Some("Add 4".to_owned()));
+        store.undo();
+        assert_eq!(store.undo_description(), None);
+        assert_eq!(store.redo_description(), Some("Add 4".to_owned()));
     }
 
-    fn _undo(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
-        let (seq_no, ser_cmd) = self.persister_client.undo()?;
-        let cmd: C = bincode::deserialize(&ser_cmd).map_err(|ser_err|
-            SqliteUndoStoreError::CannotDeserialize {
-                path: Some(self.base_dir.clone()), seq_no, ser_err
-            }
-        )?;
-        cmd.undo(&mut self.model);
-        Ok(())
+    #[test]
+    fn cannot_encrypt_existing_records() {
+        use std::sync::Arc;
+        use tempfile::tempdir;
 
-        // let mut stmt = self.db_undo(|| self.conn.prepare(
-        //     "select serialized from command where command_id = ?1"
-        // ).map_err(Report::from))?;
-        // let mut rows = self.db_undo(|| stmt.query(rusqlite::params![self.cur_cmd_seq_no]).map_err(Report::from))?;
-        // if let Some(row) = self.db_undo(|| rows.next().map_err(Report::from))? {
-        //     let serialized: Vec<u8> = self.db_undo(|| row.get(0).map_err(Report::from))?;
-        //     let cmd: C = bincode::deserialize(&serialized).map_err(|ser_err|
-        //         SqliteUndoStoreError::CannotDeserialize {
-        //             path: Some(self.sqlite_path.clone()), id: self.cur_cmd_seq_no, ser_err
-        //         }
-        //     )?;
-        //     let res = cmd.undo(&mut self.model);
-        //     self.cur_cmd_seq_no -= 1;
-        //     self.save_seq_no()?;
-        //     Ok(res)
-        // } else {
-        //     error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo)
-        // }
-    }
-
-    fn _redo(&mut self) -> Result<(), Report<SqliteUndoStoreError>> {
-        let (seq_no, ser_cmd) = self.persister_client.redo()?;
-        let cmd: C = bincode::deserialize(&ser_cmd).map_err(|ser_err|
-            SqliteUndoStoreError::CannotDeserialize {
-                path: Some(self.base_dir.clone()), seq_no, ser_err
-            }
-        )?;
-        cmd.redo(&mut self.model);
-
-        // let mut  stmt = self.db_undo(|| self.conn.prepare(
-        //     "select serialized from command where command_id = ?1"
-        // ).map_err(Report::from))?;
-        // let mut rows = self.db_undo(|| stmt.query(rusqlite::params![self.cur_cmd_seq_no + 1]).map_err(Report::from))?;
-        // if let Some(row) = self.db_undo(|| rows.next().map_err(Report::from))? {
-        //     let serialized: Vec<u8> = self.db_undo(|| row.get(0).map_err(Report::from))?;
-        //     let cmd: C = bincode::deserialize(&serialized).map_err(|ser_err|
-        //         SqliteUndoStoreError::CannotDeserialize {
-        //             path: Some(self.sqlite_path.clone()), id: self.cur_cmd_seq_no, ser_err
-        //         }
-        //     )?;
-
-        //     cmd.redo(&mut self.model);
-        //     self.cur_cmd_seq_no += 1;
-        //     self.save_seq_no()?;
-        //     Ok(())
-        // } else {
-        //     error_stack::bail!(SqliteUndoStoreError::CannotUndoRedo)
-        // }
-Ok(())
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        store.wait_until_saved().unwrap();
+        drop(store);
+
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(
+            dir.clone(), undo_store::Options::new().with_cipher(Arc::new(XorCipher(1)))
+        ).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::NotEncrypted(_)), "{:?}", err);
     }
-}
 
-pub const MAX_COMMAND_ID: i64 = 9_223_372_036_854_775_807;
+    #[test]
+    fn can_switch_branches() {
+        use tempfile::tempdir;
+        use super::BranchInfo;
 
-// #[cfg(feature = "persistence")]
-// impl<C, M, E> SqliteUndoStore<C, M, E>
-//   where M: Default + serde::Serialize + serde::de::DeserializeOwned + 'static, C: crate::cmd::SerializableCmd<Model = M>
-// {
-// }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_undo_tree();
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        store.add(1).unwrap();
+        store.add(2).unwrap();
+        store.add(4).unwrap();
+        store.undo();
+        store.undo();
+        store.add(10).unwrap();
+        store.undo();
+        store.add(20).unwrap();
+        // 1 -+- 2 - 4    (branch 1)
+        //    +- 10       (branch 2)
+        //    +- 20
+        assert_eq!(store.model().value(), 21);
+        assert_eq!(store.branches().unwrap(), vec![
+            BranchInfo { id: 1, parent_id: None, fork: 1, len: 2 },
+            BranchInfo { id: 2, parent_id: None, fork: 1, len: 1 },
+        ]);
+
+        store.switch_branch(1).unwrap();
+        assert_eq!(store.model().value(), 7);
+        assert!(!store.can_redo());
+        store.undo();
+        store.add(100).unwrap();
+        // 1 -+- 2 -+- 100
+        //    |     +- 4  (branch 4)
+        //    +- 10       (branch 2)
+        //    +- 20       (branch 3)
+        assert_eq!(store.model().value(), 103);
 
-#[cfg(feature = "persistence")]
-impl<C, M, E> UndoStore for SqliteUndoStore<C, M, E>
-  where M: Default + serde::Serialize + serde::de::DeserializeOwned + 'static, C: crate::cmd::SerializableCmd<Model = M>
-{
-    type ModelType = M;
-    type CmdType = C;
-    type ErrType = E;
+        store.switch_branch(3).unwrap();
+        assert_eq!(store.model().value(), 21);
+        // The history 2 - 100 becomes branch 5 and branch 4 moves under it.
+        assert_eq!(store.branches().unwrap(), vec![
+            BranchInfo { id: 2, parent_id: None, fork: 1, len: 1 },
+            BranchInfo { id: 4, parent_id: Some(5), fork: 1, len: 1 },
+            BranchInfo { id: 5, parent_id: None, fork: 1, len: 2 },
+        ]);
+        drop(store);
 
-    fn model(&self) -> &M { &self.model }
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.model().value(), 21);
+        assert_eq!(store.branches().unwrap().len(), 3);
+        store.switch_branch(4).unwrap();
+        assert_eq!(store.model().value(), 7);
+        drop(store);
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.model().value(), 7);
+        store.undo();
+        assert_eq!(store.model().value(), 3);
+        store.undo();
+        store.undo();
+        assert_eq!(store.model().value(), 0);
+        assert!(!store.can_undo());
+        let err = store.switch_branch(4).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::BranchNotFound(4)), "{:?}", err);
+    }
+
+    #[test]
+    fn clean_point_moves_with_branch() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_undo_tree();
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        store.add(1).unwrap();
+        store.add(2).unwrap();
+        store.mark_clean();
+        store.add(4).unwrap();
+        store.undo();
+        store.undo();
+        store.add(10).unwrap();
+        // 1 -+- 2 (clean) - 4 (branch 1)
+        //    +- 10
+        assert!(store.is_dirty());
+
+        store.switch_branch(1).unwrap();
+        assert!(store.is_dirty());
+        store.undo();
+        assert_eq!(store.model().value(), 3);
+        assert!(!store.is_dirty());
+        drop(store);
 
-    fn mutate(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> Result<Self::CmdType, Self::ErrType>>) -> Result<(), Self::ErrType> {
-        match f(&mut self.model) {
-            Ok(cmd) => {
-                if let Err(e) = self._add_cmd(cmd) {
-                    panic!("Cannot contact persister server {:?}.", e);
-                }
-                Ok(())
-            }
-            Err(err) => {
-                Err(err)
-            }
-        }
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert!(!store.is_dirty());
+        store.undo();
+        store.add(20).unwrap();
+        // The clean point moves into branch 3 again.
+        store.switch_branch(3).unwrap();
+        assert_eq!(store.model().value(), 7);
+        store.undo();
+        assert!(!store.is_dirty());
     }
 
-    fn add_cmd(&mut self, cmd: Self::CmdType) {
-        cmd.redo(&mut self.model);
+    #[test]
+    fn can_list_history() {
+        use tempfile::tempdir;
+        use super::HistoryEntry;
 
-        match self._add_cmd(cmd) {
-            Err(e) => {
-                panic!("Cannot contact persister server {:?}.", e);
-            }
-            Ok(_) => ()
-        }
-    }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(3)).unwrap();
+        assert_eq!(store.history().unwrap().count(), 0);
+        store.add(1).unwrap();
+        store.add(2).unwrap();
+        store.add(3).unwrap();
+        store.add(4).unwrap(); // Add(1) is removed.
+        store.undo();
+        let expected = vec![
+            HistoryEntry { seq_no: 2, undoable: true, grouped: false, cmd: SerSumCmd::Add(2) },
+            HistoryEntry { seq_no: 3, undoable: true, grouped: false, cmd: SerSumCmd::Add(3) },
+            HistoryEntry { seq_no: 4, undoable: false, grouped: false, cmd: SerSumCmd::Add(4) },
+        ];
+        assert_eq!(store.history().unwrap().collect::<Vec<_>>(), expected);
+        drop(store);
 
-    fn undo(&mut self) {
-        if self.can_undo() {
-            if let Err(e) = self._undo() {
-                panic!("Cannot contact persister server {:?}.", e);
-            }
-        }
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open_read_only(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.history().unwrap().collect::<Vec<_>>(), expected);
     }
 
-    fn can_undo(&self) -> bool {
-        self.persister_client.can_undo()
+    #[test]
+    fn can_group_cmds() {
+        use tempfile::tempdir;
 
-        // let mut stmt = self.db_can_undo(|| self.conn.prepare(
-        //     "select 1 from command where command_id <= ?1"
-        // ).map_err(Report::from))?;
-        // let mut rows = self.db_can_undo(|| stmt.query(rusqlite::params![self.cur_cmd_seq_no]).map_err(Report::from))?;
-        // let row = self.db_can_undo(|| rows.next().map_err(Report::from))?;
-        // Ok(row.is_some())
-    }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_merge_timeout(Duration::from_secs(60));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        store.add(1).unwrap();
+        store.group(|store| {
+            store.add(2).unwrap(); // Not merged across the group boundary.
+            store.begin_group();
+            store.sub(3).unwrap();
+            store.end_group();
+            store.add(4).unwrap();
+        });
+        store.add(5).unwrap(); // Not merged into the group.
+        assert_eq!(store.model().value(), 9);
 
-    fn can_redo(&self) -> bool {
-        self.persister_client.can_redo()
+        store.undo();
+        assert_eq!(store.model().value(), 4);
+        store.undo();
+        assert_eq!(store.model().value(), 1);
+        drop(store);
 
-        // let mut stmt = self.db_can_undo(|| self.conn.prepare(
-        //     "select 1 from command where ?1 < command_id"
-        // ).map_err(Report::from))?;
-        // let mut rows = self.db_can_undo(|| stmt.query(rusqlite::params![self.cur_cmd_seq_no]).map_err(Report::from))?;
-        // let row = self.db_can_undo(|| rows.next().map_err(Report::from))?;
-        // Ok(row.is_some())
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.history().unwrap().map(|e| e.grouped).collect::<Vec<_>>(), vec![false, false, true, true, false]);
+        store.redo();
+        assert_eq!(store.model().value(), 4);
+        store.go_to(3);
+        assert_eq!(store.model().value(), 0);
+        store.undo();
+        assert_eq!(store.model().value(), 1);
     }
 
-    // fn can_redo(&self) -> bool {
-    //     match self._can_redo() {
-    //         Ok(b) => b,
-    //         Err(e) => panic!("Cannot access database {:?}.", e),
-    //     }
-    // }
+    #[test]
+    fn group_is_described_by_first_described_cmd() {
+        use tempfile::tempdir;
 
-    fn redo(&mut self) {
-        if self.can_redo() {
-            if let Err(e) = self._redo() {
-                panic!("Cannot access database {:?}.", e);
-            }
-        }
-    }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_merge_timeout(Duration::from_secs(60));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        store.add(1).unwrap();
+        store.group(|store| {
+            store.sub(2).unwrap();
+            assert_eq!(store.undo_description(), None);
+            store.add(3).unwrap();
+            store.sub(4).unwrap();
+            store.add(5).unwrap();
+            store.add(6).unwrap(); // Merged into Add 11.
+        });
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        store.add(7).unwrap();
+        assert_eq!(store.undo_description(), Some("Add 7".to_owned()));
+        store.undo();
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        assert_eq!(store.redo_description(), Some("Add 7".to_owned()));
+        store.undo();
+        assert_eq!(store.undo_description(), Some("Add 1".to_owned()));
+        assert_eq!(store.redo_description(), Some("Add 3".to_owned()));
+        drop(store);
 
-    fn irreversible_mutate<R>(&mut self, f: Box<dyn FnOnce(&mut Self::ModelType) -> R>) -> R {
-        f(&mut self.model)
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.redo_description(), Some("Add 3".to_owned()));
+        store.redo();
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        assert_eq!(store.redo_description(), Some("Add 7".to_owned()));
     }
-}
-#[cfg(test)]
-mod tests {
-    use super::{Cmd, InMemoryUndoStore, UndoStore};
 
-    enum SumCmd {
-        Add(i32), Sub(i32),
-    }
+    #[test]
+    fn failed_transactional_mutation_is_rolled_back() {
+        use tempfile::tempdir;
 
-    #[derive(PartialEq, Debug)]
-    struct Sum(i32);
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        let result = store.mutate_transactionally(Box::new(|model| { model.value += 10; Err(()) }));
+        assert_eq!(result, Err(()));
+        assert_eq!(store.model().value(), 1);
+        drop(store);
 
-    impl Default for Sum {
-        fn default() -> Self {
-            Self(0)
-        }
+        // The model agrees with the persisted history.
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.model().value(), 1);
     }
 
-    impl Cmd for SumCmd {
-        type Model = Sum;
+    #[test]
+    fn transactional_mutation_is_rolled_back_on_store_error() {
+        use tempfile::tempdir;
 
-        fn redo(&self, model: &mut Self::Model) {
-            match self {
-                SumCmd::Add(i) => model.0 += *i,
-                SumCmd::Sub(i) => model.0 -= *i,
-            }
-        }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        wait_add_cmd_completion(&mut store);
 
-        fn undo(&self, model: &mut Self::Model) {
-            match self {
-                SumCmd::Add(i) => model.0 -= *i,
-                SumCmd::Sub(i) => model.0 += *i,
-            }
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("drop table command", []).unwrap();
         }
-    }
 
-    trait Model {
-        type Resp;
-        fn add(&mut self, to_add: i32) -> Self::Resp;
-        fn sub(&mut self, to_sub: i32) -> Self::Resp;
+        store.add(2).unwrap();
+        assert_eq!(wait_persist_failure(&mut store), 2);
+        let err = store.try_mutate_transactionally(Box::new(|model| { model.value += 10; Ok(SerSumCmd::Add(10)) })).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::PersistFailed { seq_no: 2 }), "{:?}", err);
+        assert_eq!(store.model().value(), 3);
     }
 
-    impl Model for InMemoryUndoStore<SumCmd, Sum, ()> {
-        type Resp = ();
+    // Fails to encrypt the blobs containing the marker.
+    struct MarkerFailCipher(&'static [u8]);
 
-        fn add(&mut self, to_add: i32) {
-            self.add_cmd(SumCmd::Add(to_add));
+    impl crate::cipher::Cipher for MarkerFailCipher {
+        fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, crate::cipher::CipherError> {
+            if plain.windows(self.0.len()).any(|w| w == self.0) {
+                Err("marker found".into())
+            } else {
+                Ok(plain.to_vec())
+            }
         }
 
-        fn sub(&mut self, to_sub: i32) {
-            self.add_cmd(SumCmd::Sub(to_sub));
+        fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, crate::cipher::CipherError> {
+            Ok(encrypted.to_vec())
         }
     }
 
     #[test]
-    fn can_undo_in_memory_store() {
-        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
-        assert_eq!(store.can_undo(), false);
-        store.add(3);
-        assert_eq!(store.model().0, 3);
-
-        assert_eq!(store.can_undo(), true);
-        store.undo();
-        assert_eq!(store.model().0, 0);
+    fn create_from_reports_persist_error_passed_to_handler() {
+        use std::{cell::RefCell, rc::Rc, sync::Arc};
+        use tempfile::tempdir;
+        use super::{InMemoryUndoStore, SqliteUndoStoreError};
 
-        assert_eq!(store.can_undo(), false);
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let marker = 0x12345678;
+        let mut in_memory: InMemoryUndoStore<SerSumCmd, SerSum, ()> = InMemoryUndoStore::new(10);
+        in_memory.add_cmd(SerSumCmd::Add(1));
+        in_memory.add_cmd(SerSumCmd::Add(marker));
+
+        let seq_nos: Rc<RefCell<Vec<i64>>> = Rc::new(RefCell::new(vec![]));
+        let seq_nos2 = seq_nos.clone();
+        let options = undo_store::Options::new()
+            .with_cipher(Arc::new(MarkerFailCipher(&[0x78, 0x56, 0x34, 0x12])))
+            .with_on_persist_error(Box::new(move |err| seq_nos2.borrow_mut().push(err.seq_no)));
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::create_from(dir.clone(), options, &in_memory).err().unwrap();
+        assert!(matches!(err.current_context(), SqliteUndoStoreError::PersistFailed { seq_no: 2 }), "{:?}", err);
+        assert_eq!(*seq_nos.borrow(), [2]);
     }
 
     #[test]
-    fn can_undo_redo_in_memory_store() {
-        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
-        assert_eq!(store.can_undo(), false);
-        store.add(3);
+    fn can_convert_in_memory_store() {
+        use tempfile::tempdir;
+        use super::{InMemoryUndoStore, SqliteUndoStoreError};
 
-        assert_eq!(store.can_undo(), true);
-        assert_eq!(store.can_redo(), false);
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut in_memory: InMemoryUndoStore<SerSumCmd, SerSum, ()> = InMemoryUndoStore::new(4);
+        in_memory.add_cmd(SerSumCmd::Add(1));
+        in_memory.add_cmd(SerSumCmd::Add(2));
+        in_memory.group(|store| {
+            store.add_cmd(SerSumCmd::Sub(3));
+            store.add_cmd(SerSumCmd::Add(4));
+        });
+        in_memory.add_cmd(SerSumCmd::Add(5)); // Add(1) is removed due to the capacity.
+        in_memory.mark_clean();
+        in_memory.undo();
+        assert_eq!(in_memory.model().value(), 4);
+
+        // Add(2) is dropped due to the undo limit.
+        let options = || undo_store::Options::new().with_undo_limit(3);
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::create_from(dir.clone(), options(), &in_memory).unwrap();
+        assert_eq!(store.model().value(), 4);
+        assert_eq!(store.seq_no_range(), 2..=5);
+        assert_eq!(store.seq_no(), 4);
+        assert!(store.is_dirty());
+        drop(store);
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.model().value(), 4);
+        assert_eq!(store.seq_no(), 4);
+        store.redo();
+        assert_eq!(store.model().value(), 9);
+        assert!(!store.is_dirty());
         store.undo();
-        assert_eq!(store.model().0, 0);
+        store.undo(); // Sub(3) and Add(4) are undone together.
+        assert_eq!(store.model().value(), 3);
+        assert!(!store.can_undo());
 
-        assert_eq!(store.can_undo(), false);
-        assert_eq!(store.can_redo(), true);
         store.redo();
-        assert_eq!(store.model().0, 3);
+        let mut exported: InMemoryUndoStore<SerSumCmd, SerSum, ()> = InMemoryUndoStore::new(10);
+        store.export_to(&mut exported).unwrap();
+        assert_eq!(exported.model().value(), 4);
+        assert_eq!(exported.seq_no_range(), 2..=5);
+        assert_eq!(exported.seq_no(), 4);
+        exported.redo();
+        assert_eq!(exported.model().value(), 9);
+        assert!(!exported.is_dirty());
+        exported.undo();
+        exported.undo();
+        assert_eq!(exported.model().value(), 3);
+        assert!(!exported.can_undo());
+        drop(store);
 
-        assert_eq!(store.can_undo(), true);
-        assert_eq!(store.can_redo(), false);
-        store.undo();
-        assert_eq!(store.model().0, 0);
+        let err = SqliteUndoStore::<SerSumCmd, SerSum, ()>::create_from(dir.clone(), options(), &in_memory).err().unwrap();
+        assert!(matches!(err.current_context(), SqliteUndoStoreError::StoreExists(_)));
     }
 
     #[test]
-    fn undo_and_add_cmd() {
-        let mut store: InMemoryUndoStore<SumCmd, Sum, ()> = InMemoryUndoStore::new(3);
-
-        store.add(3);
-        store.add(4);
-        store.add(5);
-
-        // 3, 4, 5
-        assert_eq!(store.model().0, 12);
-        store.undo(); // Undo0 Cancel Add(5)
-        // 3, 4
-        assert_eq!(store.model().0, 7);
-
-        store.add(6);
-        // 3, 4, 6
-        assert_eq!(store.model().0, 13);
-
-        store.undo(); // Cancel Add(6)
-        // 3, 4
-        assert_eq!(store.model().0, 7);
-
-        store.undo(); // Cancel Undo0
-        // 3
-        assert_eq!(store.model().0, 3);
-    }
-}
+    fn can_track_dirty() {
+        use tempfile::tempdir;
 
-#[cfg(feature = "persistence")]
-#[cfg(test)]
-mod persistent_tests {
-    use std::{thread, time::Duration};
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_undo_limit(2);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert!(!store.is_dirty());
+        store.add(1).unwrap();
+        assert!(store.is_dirty());
+        store.mark_clean();
+        store.add(2).unwrap();
+        assert!(store.is_dirty());
+        store.undo();
+        assert!(!store.is_dirty());
+        drop(store);
 
-    use tracing::level_filters::LevelFilter;
-    use crate::undo_store::{self, SQLITE_FILE_NAME};
-    use super::{Cmd, SqliteUndoStore, UndoStore};
+        // The clean point is persisted.
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert!(!store.is_dirty());
+        store.redo();
+        assert!(store.is_dirty());
+        store.go_to(0);
+        store.add(3).unwrap(); // The clean point is discarded with the redo history.
+        drop(store);
 
-    #[derive(serde::Serialize, serde::Deserialize)]
-    enum Trace {
-        Add, Sub
-    }
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert!(store.is_dirty());
+        store.mark_clean();
+        store.add(4).unwrap();
+        store.add(5).unwrap();
+        store.add(6).unwrap(); // The clean point exceeds the undo limit.
+        store.go_to(2);
+        assert!(store.is_dirty());
+        drop(store);
 
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct SerSum {
-        pub value: i32,
-        pub trace: Vec<Trace>,
-        #[serde(skip)]
-        pub trace_count: usize,
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.seq_no(), 2);
+        assert!(store.is_dirty());
     }
 
-    impl SerSum {
-        fn value(&self) -> i32 {
-            self.value
-        }
-
-        fn trace_count(&self) -> usize {
-            self.trace_count
-        }
-
-        fn add(&mut self, i: i32) {
-            let new_val = self.value + i;
-            self.value = new_val;
-            self.trace.push(Trace::Add);
-            self.trace_count += 1;
-        }
-
-        fn sub(&mut self, i: i32) {
-            let new_val = self.value - i;
-            self.value = new_val;
-            self.trace.push(Trace::Sub);
-            self.trace_count += 1;
-        }
-    }
+    #[test]
+    fn can_observe() {
+        use std::{cell::RefCell, rc::Rc};
+        use tempfile::tempdir;
+        use super::StoreEvent;
 
-    impl Default for SerSum {
-        fn default() -> Self {
-            Self { value: 0, trace: vec![], trace_count: 0 }
-        }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let events = Rc::new(RefCell::new(vec![]));
+        let events_ = events.clone();
+        let options = undo_store::Options::new().with_undo_limit(3).with_merge_timeout(Duration::from_secs(60))
+            .with_observer(Box::new(move |event| events_.borrow_mut().push(event.clone())));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options).unwrap();
+        store.add(1).unwrap();
+        store.add(2).unwrap(); // Merged into Add(3)
+        store.group(|store| {
+            store.sub(3).unwrap();
+            store.add(4).unwrap();
+        });
+        store.undo();
+        store.redo();
+        store.go_to(1);
+        store.sub(5).unwrap();
+        store.add(6).unwrap();
+        store.sub(7).unwrap(); // Add(3) exceeds the undo limit.
+        store.wait_until_saved().unwrap();
+
+        // Saved is notified asynchronously.
+        let (saved, events): (Vec<_>, Vec<_>) = events.borrow().iter().cloned().partition(|e| matches!(e, StoreEvent::Saved { .. }));
+        assert_eq!(saved.last(), Some(&StoreEvent::Saved { seq_no: 4 }));
+        assert_eq!(events, vec![
+            StoreEvent::SnapshotRestored { seq_no: 0 },
+            StoreEvent::CmdAdded { seq_no: 1, merged: false },
+            StoreEvent::CmdAdded { seq_no: 1, merged: true },
+            StoreEvent::CmdAdded { seq_no: 2, merged: false },
+            StoreEvent::CmdAdded { seq_no: 3, merged: false },
+            StoreEvent::Undone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 2 },
+            StoreEvent::Redone { seq_no: 2 },
+            StoreEvent::Redone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 3 },
+            StoreEvent::Undone { seq_no: 2 },
+            StoreEvent::HistoryTruncated { seq_nos: 2..=3 },
+            StoreEvent::CmdAdded { seq_no: 2, merged: false },
+            StoreEvent::CmdAdded { seq_no: 3, merged: false },
+            StoreEvent::HistoryTruncated { seq_nos: 1..=1 },
+            StoreEvent::CmdAdded { seq_no: 4, merged: false },
+        ]);
+
+        let id = store.subscribe(Box::new(|_| panic!("Unsubscribed.")));
+        assert!(store.unsubscribe(id));
+        store.undo();
     }
 
-    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
-    enum SerSumCmd {
-        Add(i32), Sub(i32),
-    }
+    // Adds the value. Fails if the sum becomes negative.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct CheckedAdd(i32);
 
-    impl Cmd for SerSumCmd {
+    impl Cmd for CheckedAdd {
         type Model = SerSum;
 
         fn redo(&self, model: &mut Self::Model) {
-            match self {
-                SerSumCmd::Add(i) => model.add(*i),
-                SerSumCmd::Sub(i) => model.sub(*i),
-            }
+            self.try_redo(model).unwrap();
         }
 
         fn undo(&self, model: &mut Self::Model) {
-            match self {
-                SerSumCmd::Add(i) => model.value -= *i,
-                SerSumCmd::Sub(i) => model.value += *i,
+            self.try_undo(model).unwrap();
+        }
+
+        fn try_redo(&self, model: &mut Self::Model) -> Result<(), crate::cmd::CmdError> {
+            if model.value + self.0 < 0 {
+                return Err("Negative sum.".into());
             }
+            model.value += self.0;
+            Ok(())
+        }
+
+        fn try_undo(&self, model: &mut Self::Model) -> Result<(), crate::cmd::CmdError> {
+            CheckedAdd(-self.0).try_redo(model)
         }
     }
 
-    impl crate::cmd::SerializableCmd for SerSumCmd {
+    impl crate::cmd::SerializableCmd for CheckedAdd {
     }
 
-    trait SerModel {
-        fn add(&mut self, to_add: i32) -> Result<(), super::SqliteUndoStoreError>;
-        fn sub(&mut self, to_sub: i32) -> Result<(), super::SqliteUndoStoreError>;
+    #[test]
+    fn failed_cmd_leaves_store_unchanged() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<CheckedAdd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add_cmd(CheckedAdd(1));
+        store.group(|store| {
+            store.add_cmd(CheckedAdd(2));
+            store.add_cmd(CheckedAdd(3));
+        });
+        store.irreversible_mutate(Box::new(|model| model.value = 4));
+
+        // Undoing Add(3) succeeds but Add(2) fails.
+        let err = store.try_undo().unwrap_err();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::CmdFailed { seq_no: 2, .. }));
+        assert_eq!(store.seq_no(), 3);
+        assert_eq!(store.model().value(), 4);
+
+        let err = store.try_go_to(0).unwrap_err();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::CmdFailed { seq_no: 2, .. }));
+        assert_eq!(store.seq_no(), 3);
+        assert_eq!(store.model().value(), 4);
+
+        let err = store.try_add_cmd(CheckedAdd(-5)).unwrap_err();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::CmdFailed { seq_no: 4, .. }));
+        assert!(!store.can_redo());
+        drop(store);
+
+        // The persisted position is unchanged. The irreversible mutation is not persisted.
+        let mut store = SqliteUndoStore::<CheckedAdd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.seq_no(), 3);
+        assert_eq!(store.model().value(), 6);
+        store.add_cmd(CheckedAdd(-6));
+        store.undo();
+        assert_eq!(store.model().value(), 6);
     }
 
-    impl SerModel for super::SqliteUndoStore::<SerSumCmd, SerSum, ()> {
-        fn add(&mut self, to_add: i32) -> Result<(), super::SqliteUndoStoreError> {
-            self.add_cmd(SerSumCmd::Add(to_add));
-            Ok(())
-        }
+    #[test]
+    fn can_describe() {
+        use tempfile::tempdir;
 
-        fn sub(&mut self, to_sub: i32) -> Result<(), super::SqliteUndoStoreError> {
-            self.add_cmd(SerSumCmd::Sub(to_sub));
-            Ok(())
-        }
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_merge_timeout(Duration::from_secs(60));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.undo_description(), None);
+        store.add(1).unwrap();
+        store.add(2).unwrap(); // Merged into Add(3)
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        store.sub(3).unwrap();
+        store.add(4).unwrap();
+        store.undo();
+        assert_eq!(store.undo_description(), None);
+        assert_eq!(store.redo_description(), Some("Add 4".to_owned()));
+        drop(store);
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.undo_description(), None);
+        assert_eq!(store.redo_description(), Some("Add 4".to_owned()));
+        store.go_to(1);
+        assert_eq!(store.undo_description(), Some("Add 3".to_owned()));
+        assert_eq!(store.redo_description(), None);
+        store.undo();
+        assert_eq!(store.undo_description(), None);
+        assert_eq!(store.redo_description(), Some("Add 3".to_owned()));
+        drop(store);
+
+        // Descriptions are stored so that they can be shown without deserializing commands.
+        let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+        let mut stmt = conn.prepare("select description from command order by command_id").unwrap();
+        let descriptions: Vec<Option<String>> = stmt.query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(descriptions, vec![Some("Add 3".to_owned()), None, Some("Add 4".to_owned())]);
     }
 
     #[test]
-    fn file_store_can_lock() {
+    fn can_record_cmd_attrs() {
+        use std::time::{SystemTime, UNIX_EPOCH};
         use tempfile::tempdir;
 
         let dir = tempdir().unwrap();
         let mut dir = dir.as_ref().to_path_buf();
         dir.push("klavier");
-        let store = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        let start = SystemTime::now() - Duration::from_millis(1);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        store.set_metadata(Some(b"alice".to_vec()));
+        store.add(2).unwrap();
+        let records = store.cmd_records().unwrap();
+        assert_eq!(records.iter().map(|r| (r.seq_no, r.metadata.clone())).collect::<Vec<_>>(), vec![
+            (1, None), (2, Some(b"alice".to_vec())),
+        ]);
+        assert!(records.iter().all(|r| start <= r.created_at.unwrap() && r.created_at.unwrap() <= SystemTime::now()));
+        drop(store);
 
-        let store2_err = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).err().unwrap();
-        match store2_err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
-            super::SqliteUndoStoreError::CannotLock { path: err_dir, error: _ } => {
-                let lock_file_path = crate::undo_store::PersisterServer::<SerSumCmd, SerSum, ()>::lock_file_path(&dir);
-                assert_eq!(err_dir.as_path(), lock_file_path);
-            },
-            _ => {panic!("Test failed. {:?}", store2_err)},
-        };
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            // Records of older versions.
+            conn.execute("update command set created_at = null where command_id = 1", []).unwrap();
+        }
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        let records = store.cmd_records().unwrap();
+        assert_eq!(records[0].created_at, None);
+        assert!(UNIX_EPOCH < records[1].created_at.unwrap());
+        assert_eq!(records[1].metadata, Some(b"alice".to_vec()));
+    }
+
+    #[test]
+    fn can_go_to() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = || undo_store::Options::new().with_undo_limit(3);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        for i in 1..=8 {
+            store.add(i).unwrap();
+        }
+        wait_add_cmd_completion(&mut store);
+        // [15] -cmd6(+6)-> [21] -cmd7(+7)-> [28] -cmd8(+8)-> [36]
+        //                                                    ^ snap(id=8)
+        assert_eq!(store.seq_no(), 8);
+        assert_eq!(store.seq_no_range(), 5..=8);
 
+        store.go_to(5);
+        assert_eq!(store.model().value(), 15);
+        assert!(!store.can_undo());
+        // Restored from the snapshot.
+        store.go_to(8);
+        assert_eq!(store.model().value(), 36);
+        store.go_to(6);
+        assert_eq!(store.model().value(), 21);
+        store.redo();
+        assert_eq!(store.model().value(), 28);
+        store.undo();
         drop(store);
 
-        let _ = super::SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options()).unwrap();
+        assert_eq!(store.seq_no(), 6);
+        assert_eq!(store.model().value(), 21);
+        let err = store.try_go_to(9).err().unwrap();
+        assert!(matches!(err.current_context(), super::SqliteUndoStoreError::SeqNoOutOfRange(9)), "{:?}", err);
+        store.go_to(8);
+        assert_eq!(store.model().value(), 36);
+        store.add(9).unwrap();
+        assert_eq!(store.model().value(), 45);
+        assert_eq!(store.seq_no_range(), 6..=9);
     }
 
     fn wait_add_cmd_completion(store: &mut SqliteUndoStore::<SerSumCmd, SerSum, ()>) {
@@ -1609,6 +5791,18 @@ mod persistent_tests {
         }
     }
 
+    fn wait_persist_failure(store: &mut SqliteUndoStore::<SerSumCmd, SerSum, ()>) -> i64 {
+        loop {
+            match store.saved() {
+                Ok(_) => thread::sleep(Duration::from_millis(100)),
+                Err(err) => match err.current_context() {
+                    super::SqliteUndoStoreError::PersistFailed { seq_no } => return *seq_no,
+                    _ => panic!("Unexpected error: {:?}", err),
+                }
+            }
+        }
+    }
+
     #[test]
     fn can_serialize_cmd() {
         use rusqlite::{Connection, params};
@@ -1651,6 +5845,44 @@ mod persistent_tests {
         assert!(rows.next().unwrap().is_none());
     }
 
+    #[test]
+    fn can_merge_serialized_cmds() {
+        use rusqlite::{Connection, params};
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let options = undo_store::Options::new().with_merge_timeout(Duration::from_secs(60));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options).unwrap();
+
+        store.add(1).unwrap();
+        store.add(2).unwrap(); // Merged into Add(3)
+        store.sub(4).unwrap();
+        wait_add_cmd_completion(&mut store);
+        assert_eq!(store.model().value(), -1);
+
+        {
+            let conn = Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            assert_eq!(cmd_ids(&conn), [1, 2]);
+            let serialized: Vec<u8> = conn.query_row(
+                "select serialized from command where command_id = 1", params![], |row| row.get(0)
+            ).unwrap();
+            let cmd: SerSumCmd = bincode::deserialize(&serialized).unwrap();
+            assert_eq!(cmd, SerSumCmd::Add(3));
+        }
+
+        store.undo();
+        assert_eq!(store.model().value(), 3);
+        drop(store);
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        assert_eq!(store.model().value(), 3);
+        store.undo();
+        assert_eq!(store.model().value(), 0);
+        assert!(!store.can_undo());
+    }
+
     #[test]
     fn can_undo_serialize_cmd() {
         use tempfile::tempdir;
@@ -1685,6 +5917,111 @@ mod persistent_tests {
         assert_eq!(store.model().value(), 123 - 234);
     }
 
+    #[test]
+    fn undo_error_is_returned() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.try_add_cmd(SerSumCmd::Add(1)).unwrap();
+        wait_add_cmd_completion(&mut store);
+
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("delete from command", []).unwrap();
+        }
+
+        let err = store.try_undo().err().unwrap();
+        match err.downcast_ref::<super::SqliteUndoStoreError>().unwrap() {
+            super::SqliteUndoStoreError::CannotUndoRedo => {},
+            _ => panic!("Test failed. {:?}", err),
+        }
+        assert_eq!(store.model().value(), 1);
+        assert!(store.can_undo());
+    }
+
+    #[test]
+    fn late_ack_does_not_shrink_seq_no_range() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        store.add(2).unwrap();
+        // The ack of the first command arrives while the second one is being persisted.
+        store.persister_client.process_async_resp(super::PersistResp::AddCmdOk { seq_no: 1 }).unwrap();
+        assert_eq!(store.seq_no_range(), 0..=2);
+        assert!(!store.can_redo());
+        store.go_to(2);
+        assert_eq!(store.model().value(), 3);
+        store.undo();
+        assert!(store.can_redo());
+        assert_eq!(store.seq_no_range(), 0..=2);
+    }
+
+    #[test]
+    fn persist_errors_are_queued() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new()).unwrap();
+        store.add(1).unwrap();
+        wait_add_cmd_completion(&mut store);
+        assert!(store.pending_errors().unwrap().is_empty());
+
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("drop table command", []).unwrap();
+        }
+
+        store.add(2).unwrap();
+        assert_eq!(wait_persist_failure(&mut store), 2);
+        let errors = store.pending_errors().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].seq_no, 2);
+        assert!(matches!(errors[0].error.current_context(), super::SqliteUndoStoreError::DbError(_, _)));
+        assert!(store.pending_errors().unwrap().is_empty());
+
+        // Taking the errors does not make the store saved.
+        assert_eq!(wait_persist_failure(&mut store), 2);
+        assert!(store.try_add_cmd(SerSumCmd::Add(3)).is_err());
+        assert_eq!(store.model().value(), 3);
+    }
+
+    #[test]
+    fn persist_errors_are_passed_to_handler() {
+        use std::{cell::RefCell, rc::Rc};
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let seq_nos: Rc<RefCell<Vec<i64>>> = Rc::new(RefCell::new(vec![]));
+        let seq_nos2 = seq_nos.clone();
+        let options = undo_store::Options::new().with_on_persist_error(Box::new(move |err| seq_nos2.borrow_mut().push(err.seq_no)));
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options).unwrap();
+        store.add(1).unwrap();
+        wait_add_cmd_completion(&mut store);
+
+        {
+            let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE_NAME)).unwrap();
+            conn.execute("drop table command", []).unwrap();
+        }
+
+        store.add(2).unwrap();
+        assert_eq!(wait_persist_failure(&mut store), 2);
+        assert_eq!(*seq_nos.borrow(), [2]);
+        assert!(store.pending_errors().unwrap().is_empty());
+        assert!(store.try_add_cmd(SerSumCmd::Add(3)).is_err());
+        assert_eq!(*seq_nos.borrow(), [2]);
+    }
+
     #[test]
     fn file_undo_store_can_serialize() {
         use tempfile::tempdir;
@@ -2019,6 +6356,7 @@ mod persistent_tests {
         assert_eq!(store.model().value(), 3);
     }
 
+    #[allow(dead_code)]
     pub fn enable_logging() {
         tracing_subscriber::fmt()
         .event_format(
@@ -2207,6 +6545,111 @@ mod persistent_tests {
         assert_eq!(store.model().value(), 28);
     }
 
+    #[test]
+    fn can_compact() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let sqlite_path = dir.join(SQLITE_FILE_NAME);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(3)).unwrap();
+        for i in 1..=6 {
+            store.add(i).unwrap();
+        }
+        wait_add_cmd_completion(&mut store);
+        // [6] -cmd4(+4)-> [10] -cmd5(+5)-> [15] -cmd6(+6)-> [21]
+        //                 ^ snap(id=4)
+        {
+            let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+            assert_eq!(cmd_ids(&conn), [4, 5, 6]);
+            assert_eq!(snapshot_ids(&conn), [4]);
+        }
+
+        store.compact().unwrap();
+        // [6] -cmd1(+4)-> [10] -cmd2(+5)-> [15] -cmd3(+6)-> [21]
+        //                 ^ snap(id=1)
+        {
+            let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+            assert_eq!(cmd_ids(&conn), [1, 2, 3]);
+            assert_eq!(snapshot_ids(&conn), [1]);
+        }
+        assert_eq!(store.model().value(), 21);
+
+        store.undo();
+        store.undo();
+        store.undo();
+        assert_eq!(store.model().value(), 6);
+        assert!(!store.can_undo());
+        drop(store);
+
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(3)).unwrap();
+        assert_eq!(store.model().value(), 6);
+        store.redo();
+        assert_eq!(store.model().value(), 10);
+        store.add(7).unwrap();
+        wait_add_cmd_completion(&mut store);
+        assert_eq!(store.model().value(), 17);
+        {
+            let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+            assert_eq!(cmd_ids(&conn), [1, 2]);
+        }
+    }
+
+    #[test]
+    fn can_compact_automatically() {
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let sqlite_path = dir.join(SQLITE_FILE_NAME);
+        let options = undo_store::Options::new().with_undo_limit(2).with_auto_compaction(5);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options).unwrap();
+        for i in 1..=10 {
+            store.add(i).unwrap();
+        }
+        wait_add_cmd_completion(&mut store);
+        assert_eq!(store.model().value(), 55);
+        {
+            let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+            assert!(cmd_ids(&conn).iter().all(|id| *id < 5));
+        }
+
+        store.undo();
+        assert_eq!(store.model().value(), 45);
+        drop(store);
+
+        let store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), undo_store::Options::new().with_undo_limit(2)).unwrap();
+        assert_eq!(store.model().value(), 45);
+    }
+
+    #[test]
+    fn auto_compaction_threshold_is_raised_above_undo_limit() {
+        use tempfile::tempdir;
+
+        let options = undo_store::Options::<SerSum>::new().with_undo_limit(10).with_auto_compaction(5);
+        assert_eq!(options.auto_compaction_threshold, Some(12));
+        let options = undo_store::Options::<SerSum>::new().with_undo_limit(2).with_auto_compaction(5);
+        assert_eq!(options.auto_compaction_threshold, Some(5));
+
+        // The field set directly is raised when a command is added.
+        let dir = tempdir().unwrap();
+        let mut dir = dir.as_ref().to_path_buf();
+        dir.push("klavier");
+        let sqlite_path = dir.join(SQLITE_FILE_NAME);
+        let mut options = undo_store::Options::new().with_undo_limit(2);
+        options.auto_compaction_threshold = Some(1);
+        let mut store = SqliteUndoStore::<SerSumCmd, SerSum, ()>::open(dir.clone(), options).unwrap();
+        for i in 1..=4 {
+            store.add(i).unwrap();
+        }
+        wait_add_cmd_completion(&mut store);
+        assert_eq!(store.model().value(), 10);
+        let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+        assert_eq!(cmd_ids(&conn), [2, 3]);
+    }
+
     #[test]
     fn on_snapshot_restored() {
         use tempfile::tempdir;